@@ -1,5 +1,7 @@
 use anyhow::Result;
+use std::sync::Arc;
 use std::{env, io, net::Ipv4Addr, str};
+use toytcp::link::PnetLink;
 use toytcp::tcp::TCP;
 
 fn main() -> Result<()> {
@@ -16,7 +18,7 @@ fn main() -> Result<()> {
  * 今はまだ connect するだけ。
  */
 fn echo_client(remote_addr: Ipv4Addr, remote_port: u16) -> Result<()> {
-    let tcp = TCP::new();
+    let tcp = TCP::new(Arc::new(PnetLink::new()?));
     let sock_id = tcp.connect(remote_addr, remote_port)?;
     let cloned_tcp = tcp.clone();
     // Ctrl+c でクライアント側からクローズする
@@ -1,5 +1,7 @@
 use anyhow::Result;
+use std::sync::Arc;
 use std::{env, net::Ipv4Addr, str};
+use toytcp::link::PnetLink;
 use toytcp::tcp::TCP;
 
 fn main() -> Result<()> {
@@ -12,7 +14,7 @@ fn main() -> Result<()> {
 }
 
 fn echo_server(local_addr: Ipv4Addr, local_port: u16) -> Result<()> {
-    let tcp = TCP::new();
+    let tcp = TCP::new(Arc::new(PnetLink::new()?));
     let listening_socket = tcp.listen(local_addr, local_port)?;
 
     dbg!("listening...");
@@ -1,5 +1,7 @@
 use anyhow::Result;
+use std::sync::Arc;
 use std::{env, fs, net::Ipv4Addr, str};
+use toytcp::link::PnetLink;
 use toytcp::tcp::TCP;
 
 fn main() -> Result<()> {
@@ -12,7 +14,7 @@ fn main() -> Result<()> {
 }
 
 fn file_client(remote_addr: Ipv4Addr, remote_port: u16, filepath: &str) -> Result<()> {
-    let tcp = TCP::new();
+    let tcp = TCP::new(Arc::new(PnetLink::new()?));
     let sock_id = tcp.connect(remote_addr, remote_port)?;
     let cloned_tcp = tcp.clone();
     ctrlc::set_handler(move || {
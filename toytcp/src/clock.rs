@@ -0,0 +1,85 @@
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// プロトコルのタイマーが参照する時計。
+/// 単調増加することだけを前提とし、時刻は時計ごとの起点からの経過時間で表す。
+pub trait Clock: Send + Sync {
+    /// 起点からの経過時間を返す。
+    fn now(&self) -> Duration;
+
+    /// この時計で duration が経過するまでスレッドを待機させる。
+    fn sleep(&self, duration: Duration);
+}
+
+/// OS の単調時計 (`Instant`) に従う時計
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// `advance` を呼んだときにだけ進む時計。
+/// タイムアウトを実時間で待たずに、タイマーの挙動を決定的にテストするために使う。
+pub struct ManualClock {
+    now: Mutex<Duration>,
+    condvar: Condvar,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            now: Mutex::new(Duration::ZERO),
+            condvar: Condvar::new(),
+        }
+    }
+
+    /// 時計を duration だけ進め、待機中のスレッドを起こす。
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+        self.condvar.notify_all();
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        let deadline = *now + duration;
+        // 他のスレッドが advance で deadline まで進めるまで待機
+        while *now < deadline {
+            now = self.condvar.wait(now).unwrap();
+        }
+    }
+}
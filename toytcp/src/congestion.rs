@@ -0,0 +1,669 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::time::Duration;
+
+/// 輻輳制御アルゴリズム。
+/// コネクションは、ack されていないバイト数がこの輻輳ウィンドウ (cwnd) に収まる範囲でしか送信しない。
+/// 各メソッドの now は `Clock::now` の値。
+pub trait CongestionControl: Debug + Send + Sync {
+    /// 新しいデータが acked バイト ack された。rtt はこの ACK で計測できた RTT。
+    /// 高速リカバリ中の ACK では呼ばれない。
+    fn on_ack(&mut self, acked: usize, rtt: Option<Duration>, now: Duration);
+
+    /// 重複 ACK でセグメントのロスを検出し、高速リカバリに入った。
+    /// flight_size はその時点で送信済みで ack されていないバイト数。
+    fn on_loss(&mut self, flight_size: usize, now: Duration);
+
+    /// SACK を使わない高速リカバリ (NewReno, RFC 6582) 中に、重複 ACK が届いた。
+    /// 重複 ACK 1つごとに1セグメントが相手に届いてネットワークから出ていったので、その分だけ新しいセグメントを送れるようにする。
+    /// リカバリに入ったときも、それまでに届いた重複 ACK の数だけ呼ばれる。
+    fn on_duplicate_ack(&mut self, _now: Duration) {}
+
+    /// SACK を使わない高速リカバリ中に、ロスを検出した時点で送信済みのデータの一部だけを ack する partial ACK が届いた。
+    /// acked はこの ACK で新しく ack されたバイト数。
+    fn on_partial_ack(&mut self, _acked: usize, _now: Duration) {}
+
+    /// 高速リカバリを終えた。
+    fn on_recovery_end(&mut self, _now: Duration) {}
+
+    /// 再送タイムアウトが起きた。
+    fn on_rto(&mut self, flight_size: usize, now: Duration);
+
+    /// 輻輳ウィンドウのバイト数
+    fn cwnd(&self) -> usize;
+
+    /// スロースタートを終える閾値のバイト数。閾値を持たないアルゴリズムは usize::MAX を返す。
+    fn ssthresh(&self) -> usize;
+
+    /// セグメントを送り出す間隔を決める送信レート (バイト/秒)。None であればペーシングせず、送れるだけ続けて送る。
+    fn pacing_rate(&self) -> Option<u64>;
+}
+
+/// ソケットごとに選択する輻輳制御アルゴリズムの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CongestionAlgorithm {
+    #[default]
+    Reno,
+    Cubic,
+    Bbr,
+}
+
+impl CongestionAlgorithm {
+    /// mss バイトのセグメントを送るコネクション用に、アルゴリズムの状態を初期化して返す。
+    pub fn build(self, mss: usize) -> Box<dyn CongestionControl> {
+        match self {
+            CongestionAlgorithm::Reno => Box::new(Reno::new(mss)),
+            CongestionAlgorithm::Cubic => Box::new(Cubic::new(mss)),
+            CongestionAlgorithm::Bbr => Box::new(Bbr::new(mss)),
+        }
+    }
+}
+
+/// RFC 5681 の初期ウィンドウ。セグメントのサイズに応じて 2〜4 セグメント分にする。
+pub fn initial_window(mss: usize) -> usize {
+    match mss {
+        0..=1095 => 4 * mss,
+        1096..=2190 => 3 * mss,
+        _ => 2 * mss,
+    }
+}
+
+/// Reno の輻輳制御 (RFC 5681)。
+/// 高速リカバリ中は NewReno (RFC 6582) と同じく、重複 ACK ごとにウィンドウを広げ、partial ACK で ack された分だけ戻す。
+#[derive(Debug, Clone)]
+pub struct Reno {
+    mss: usize,
+    cwnd: usize,
+    ssthresh: usize,
+    // 輻輳回避中に、cwnd を1セグメント分増やすまでに ack されたバイト数
+    bytes_acked: usize,
+}
+
+impl Reno {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window(mss),
+            // 最初はネットワークの容量が分からないので、ロスが起きるまでスロースタートを続ける
+            ssthresh: usize::MAX,
+            bytes_acked: 0,
+        }
+    }
+
+    /// ロスを検出したときの ssthresh。送信中のデータ量の半分にする。
+    fn reduced_ssthresh(&self, flight_size: usize) -> usize {
+        cmp::max(flight_size / 2, 2 * self.mss)
+    }
+}
+
+impl CongestionControl for Reno {
+    fn on_ack(&mut self, acked: usize, _rtt: Option<Duration>, _now: Duration) {
+        if self.cwnd < self.ssthresh {
+            // スロースタート: ack されたバイト数だけ増やす。1つの ACK で増やすのは最大1セグメント分
+            self.cwnd += cmp::min(acked, self.mss);
+        } else {
+            // 輻輳回避: 1 RTT で1セグメント分増やす
+            self.bytes_acked += acked;
+            if self.bytes_acked >= self.cwnd {
+                self.bytes_acked -= self.cwnd;
+                self.cwnd += self.mss;
+            }
+        }
+    }
+
+    fn on_loss(&mut self, flight_size: usize, _now: Duration) {
+        self.ssthresh = self.reduced_ssthresh(flight_size);
+        self.cwnd = self.ssthresh;
+        self.bytes_acked = 0;
+    }
+
+    fn on_duplicate_ack(&mut self, _now: Duration) {
+        self.cwnd += self.mss;
+    }
+
+    fn on_partial_ack(&mut self, acked: usize, _now: Duration) {
+        // 再送したセグメント分の1セグメントは送れるように残す
+        self.cwnd = self.cwnd.saturating_sub(acked);
+        if acked >= self.mss {
+            self.cwnd += self.mss;
+        }
+    }
+
+    fn on_recovery_end(&mut self, _now: Duration) {
+        // 広げた分を戻して、ロスを検出したときに下げた大きさから輻輳回避を再開する
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, flight_size: usize, _now: Duration) {
+        self.ssthresh = self.reduced_ssthresh(flight_size);
+        // ネットワークの状態が分からなくなったので、1セグメントからスロースタートし直す
+        self.cwnd = self.mss;
+        self.bytes_acked = 0;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+// CUBIC のパラメータ (RFC 9438)。ウィンドウはセグメント数、時間は秒で計算する。
+const CUBIC_C: f64 = 0.4;
+const CUBIC_BETA: f64 = 0.7;
+// Reno と同じ平均スループットになるように、Reno 相当のウィンドウを増やす量
+const CUBIC_ALPHA: f64 = 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA);
+
+/// CUBIC の輻輳制御 (RFC 9438)。
+/// ロスを検出したときのウィンドウ W_max を基準に、経過時間の3次関数でウィンドウを増やす。
+/// W_max の手前では緩やかに、離れるほど急に増やすので、遅延の大きい経路でも Reno よりも早くウィンドウを回復できる。
+/// スロースタートは HyStart++ で、RTT の増加を検出した時点でロスを待たずに抜ける。
+#[derive(Debug, Clone)]
+pub struct Cubic {
+    mss: usize,
+    // 輻輳ウィンドウ (セグメント数)。1つの ACK で増える量は1セグメント未満なので小数で持つ
+    cwnd: f64,
+    ssthresh: usize,
+    // 最後にロスを検出したときのウィンドウ
+    w_max: f64,
+    // W_max に戻るまでの時間 (秒)
+    k: f64,
+    // 輻輳回避を始めた時刻と、その時点のウィンドウ
+    epoch_start: Option<Duration>,
+    cwnd_epoch: f64,
+    // 同じ状況の Reno が持つはずのウィンドウ。CUBIC の方が小さければこちらに合わせる (Reno-friendly region)
+    w_est: f64,
+    // 平滑化した RTT
+    srtt: Option<Duration>,
+    hystart: HyStart,
+}
+
+impl Cubic {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            cwnd: initial_window(mss) as f64 / mss as f64,
+            ssthresh: usize::MAX,
+            w_max: 0.0,
+            k: 0.0,
+            epoch_start: None,
+            cwnd_epoch: 0.0,
+            w_est: 0.0,
+            srtt: None,
+            hystart: HyStart::default(),
+        }
+    }
+
+    /// 輻輳回避を始めてから t 秒後のウィンドウ
+    fn w_cubic(&self, t: f64) -> f64 {
+        CUBIC_C * (t - self.k).powi(3) + self.w_max
+    }
+
+    fn in_slow_start(&self) -> bool {
+        self.cwnd() < self.ssthresh
+    }
+
+    /// ロスを検出したときに W_max と ssthresh を更新する。
+    fn reduce(&mut self) {
+        // W_max に届く前に再びロスしたのは、他のフローに帯域を譲るべき状況なので、W_max を下げて早く収束させる (fast convergence)
+        self.w_max = if self.cwnd < self.w_max {
+            self.cwnd * (1.0 + CUBIC_BETA) / 2.0
+        } else {
+            self.cwnd
+        };
+        self.ssthresh = cmp::max(
+            (self.cwnd * CUBIC_BETA * self.mss as f64) as usize,
+            2 * self.mss,
+        );
+        self.epoch_start = None;
+        self.hystart = HyStart::default();
+    }
+
+    fn congestion_avoidance(&mut self, acked: f64, now: Duration) {
+        let epoch_start = *self.epoch_start.get_or_insert_with(|| {
+            self.cwnd_epoch = self.cwnd;
+            self.w_est = self.cwnd;
+            // ロスせずに輻輳回避に入った場合は、今のウィンドウを基準にすぐ凸に増やし始める
+            self.w_max = self.w_max.max(self.cwnd);
+            self.k = ((self.w_max - self.cwnd_epoch) / CUBIC_C).cbrt();
+            now
+        });
+
+        // W_est は1 RTT あたり alpha セグメント増える。W_max を超えたら Reno と同じく1セグメントにする
+        let alpha = if self.w_est >= self.w_max {
+            1.0
+        } else {
+            CUBIC_ALPHA
+        };
+        self.w_est += alpha * acked / self.cwnd;
+
+        let rtt = self.srtt.unwrap_or_default();
+        let t = (now - epoch_start).as_secs_f64();
+        if self.w_cubic(t) < self.w_est {
+            self.cwnd = self.w_est;
+        } else {
+            // 1 RTT 後のウィンドウを目標にして、1 RTT かけて近づける。急に増えすぎないよう 1.5 倍までにする
+            let target = self
+                .w_cubic(t + rtt.as_secs_f64())
+                .clamp(self.cwnd, 1.5 * self.cwnd);
+            self.cwnd += (target - self.cwnd) / self.cwnd * acked;
+        }
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn on_ack(&mut self, acked: usize, rtt: Option<Duration>, now: Duration) {
+        if let Some(rtt) = rtt {
+            self.srtt = Some(self.srtt.map_or(rtt, |srtt| srtt * 7 / 8 + rtt / 8));
+        }
+        if self.in_slow_start() {
+            let divisor = self.hystart.on_ack(acked, rtt, self.cwnd());
+            self.cwnd += cmp::min(acked, self.mss) as f64 / self.mss as f64 / divisor as f64;
+            if self.hystart.done {
+                dbg!("hystart++: exit slow start", self.cwnd());
+                self.ssthresh = self.cwnd();
+            }
+        } else {
+            self.congestion_avoidance(acked as f64 / self.mss as f64, now);
+        }
+    }
+
+    fn on_loss(&mut self, _flight_size: usize, _now: Duration) {
+        self.reduce();
+        self.cwnd = self.ssthresh as f64 / self.mss as f64;
+    }
+
+    fn on_duplicate_ack(&mut self, _now: Duration) {
+        self.cwnd += 1.0;
+    }
+
+    fn on_partial_ack(&mut self, acked: usize, _now: Duration) {
+        self.cwnd = (self.cwnd - acked as f64 / self.mss as f64).max(0.0);
+        if acked >= self.mss {
+            self.cwnd += 1.0;
+        }
+    }
+
+    fn on_recovery_end(&mut self, _now: Duration) {
+        self.cwnd = self.ssthresh as f64 / self.mss as f64;
+    }
+
+    fn on_rto(&mut self, _flight_size: usize, _now: Duration) {
+        self.reduce();
+        self.cwnd = 1.0;
+    }
+
+    fn cwnd(&self) -> usize {
+        (self.cwnd * self.mss as f64) as usize
+    }
+
+    fn ssthresh(&self) -> usize {
+        self.ssthresh
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+// HyStart++ のパラメータ (RFC 9406)
+const HYSTART_MIN_RTT_THRESH: Duration = Duration::from_millis(4);
+const HYSTART_MAX_RTT_THRESH: Duration = Duration::from_millis(16);
+const HYSTART_MIN_RTT_DIVISOR: u32 = 8;
+const HYSTART_N_RTT_SAMPLE: usize = 8;
+const HYSTART_CSS_GROWTH_DIVISOR: usize = 4;
+const HYSTART_CSS_ROUNDS: usize = 5;
+
+/// HyStart++ (RFC 9406)。
+/// ラウンド (1 RTT) ごとの最小 RTT が前のラウンドより閾値以上増えたら、キューが溜まり始めたとみなして
+/// ウィンドウの増え方を抑えた Conservative Slow Start (CSS) に移り、CSS_ROUNDS ラウンド続いたらスロースタートを終える。
+/// ラウンドの区切りは、ラウンドを始めた時点の cwnd 分が ack されたところとする。
+#[derive(Debug, Clone, Default)]
+struct HyStart {
+    // 現在のラウンドの大きさと、そのうち ack されたバイト数
+    round_size: usize,
+    acked_in_round: usize,
+    last_round_min_rtt: Option<Duration>,
+    current_round_min_rtt: Option<Duration>,
+    rtt_sample_count: usize,
+    // CSS に入ったときの最小 RTT。CSS 中でなければ None
+    css_baseline_min_rtt: Option<Duration>,
+    css_rounds: usize,
+    // スロースタートを終えるべきかどうか
+    done: bool,
+}
+
+impl HyStart {
+    /// スロースタート中の ACK を処理して、ウィンドウの増加量を割る数を返す。
+    fn on_ack(&mut self, acked: usize, rtt: Option<Duration>, cwnd: usize) -> usize {
+        if self.acked_in_round >= self.round_size {
+            self.start_round(cwnd);
+        }
+        self.acked_in_round += acked;
+        if let Some(rtt) = rtt {
+            self.current_round_min_rtt =
+                Some(self.current_round_min_rtt.map_or(rtt, |min| min.min(rtt)));
+            self.rtt_sample_count += 1;
+        }
+
+        if self.rtt_sample_count >= HYSTART_N_RTT_SAMPLE {
+            match (
+                self.css_baseline_min_rtt,
+                self.last_round_min_rtt,
+                self.current_round_min_rtt,
+            ) {
+                (None, Some(last), Some(current)) => {
+                    let thresh = (last / HYSTART_MIN_RTT_DIVISOR)
+                        .clamp(HYSTART_MIN_RTT_THRESH, HYSTART_MAX_RTT_THRESH);
+                    if current >= last + thresh {
+                        dbg!("hystart++: enter css", current);
+                        self.css_baseline_min_rtt = Some(current);
+                        self.css_rounds = 0;
+                    }
+                }
+                (Some(baseline), _, Some(current)) if current < baseline => {
+                    // RTT の増加は一時的なものだったので、スロースタートに戻る
+                    dbg!("hystart++: resume slow start", current);
+                    self.css_baseline_min_rtt = None;
+                }
+                _ => {}
+            }
+        }
+
+        if self.css_baseline_min_rtt.is_some() {
+            HYSTART_CSS_GROWTH_DIVISOR
+        } else {
+            1
+        }
+    }
+
+    fn start_round(&mut self, cwnd: usize) {
+        self.round_size = cwnd;
+        self.acked_in_round = 0;
+        self.last_round_min_rtt = self.current_round_min_rtt.take();
+        self.rtt_sample_count = 0;
+        if self.css_baseline_min_rtt.is_some() {
+            self.css_rounds += 1;
+            self.done = self.css_rounds >= HYSTART_CSS_ROUNDS;
+        }
+    }
+}
+
+// BBR のパラメータ (draft-cardwell-iccrg-bbr-congestion-control-00)
+// スタートアップでは、1 RTT ごとに送信レートを2倍にできる 2/ln(2) 倍で送る
+const BBR_HIGH_GAIN: f64 = 2.885;
+// ProbeBW で帯域を探るときのゲインの周期。1.25 倍で探った分の余分なキューを、次の 0.75 倍で解消する
+const BBR_PACING_GAIN_CYCLE: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const BBR_CWND_GAIN: f64 = 2.0;
+// ボトルネック帯域は、直近のこのラウンド数の最大値とする
+const BBR_BTL_BW_FILTER_LEN: u64 = 10;
+// 最小 RTT がこの時間更新されなければ、ProbeRTT でキューを空にして測り直す
+const BBR_MIN_RTT_FILTER_LEN: Duration = Duration::from_secs(10);
+const BBR_PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+// 帯域が 25% 以上増えないラウンドが3回続いたら、パイプが埋まったとみなしてスタートアップを終える
+const BBR_FULL_BW_THRESH: f64 = 1.25;
+const BBR_FULL_BW_COUNT: u32 = 3;
+// RTT をまだ計測していないときに、初期の送信レートを決めるための RTT
+const BBR_INITIAL_RTT: Duration = Duration::from_millis(1);
+// cwnd の下限のセグメント数
+const BBR_MIN_PIPE_SEGMENTS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BbrMode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+/// BBR v1 の輻輳制御。
+/// ロスではなく、ACK から推定したボトルネック帯域 (BtlBw) と最小 RTT (RTprop) をもとに、
+/// 送信レートを BtlBw 付近にペーシングし、cwnd を BDP (BtlBw * RTprop) の数倍に保つ。
+/// 帯域は、ラウンド (ラウンドを始めた時点の cwnd 分が ack されるまで) ごとに、ack されたバイト数をかかった時間で割って推定する。
+#[derive(Debug, Clone)]
+pub struct Bbr {
+    mss: usize,
+    mode: BbrMode,
+    cwnd: usize,
+    pacing_gain: f64,
+    cwnd_gain: f64,
+    // (ラウンド番号, 帯域の推定値 [バイト/秒]) を直近 BBR_BTL_BW_FILTER_LEN ラウンド分
+    bw_samples: VecDeque<(u64, f64)>,
+    min_rtt: Option<Duration>,
+    min_rtt_stamp: Duration,
+    // ラウンドの番号、開始時刻、開始時点で ack されていた累計バイト数、大きさ
+    round_count: u64,
+    round_start: Option<Duration>,
+    round_start_delivered: u64,
+    round_size: u64,
+    delivered: u64,
+    // スタートアップを終えるための、帯域の伸びの判定
+    full_bw: f64,
+    full_bw_count: u32,
+    filled_pipe: bool,
+    // ProbeBW のゲインの周期の位置と、その位相を始めた時刻
+    cycle_index: usize,
+    cycle_stamp: Duration,
+    // ProbeRTT を終える時刻
+    probe_rtt_done: Option<Duration>,
+}
+
+impl Bbr {
+    pub fn new(mss: usize) -> Self {
+        Self {
+            mss,
+            mode: BbrMode::Startup,
+            cwnd: initial_window(mss),
+            pacing_gain: BBR_HIGH_GAIN,
+            cwnd_gain: BBR_HIGH_GAIN,
+            bw_samples: VecDeque::new(),
+            min_rtt: None,
+            min_rtt_stamp: Duration::ZERO,
+            round_count: 0,
+            round_start: None,
+            round_start_delivered: 0,
+            round_size: 0,
+            delivered: 0,
+            full_bw: 0.0,
+            full_bw_count: 0,
+            filled_pipe: false,
+            cycle_index: 0,
+            cycle_stamp: Duration::ZERO,
+            probe_rtt_done: None,
+        }
+    }
+
+    pub fn mode(&self) -> BbrMode {
+        self.mode
+    }
+
+    /// 推定したボトルネック帯域 (バイト/秒)
+    pub fn btl_bw(&self) -> Option<f64> {
+        self.bw_samples.iter().map(|&(_, bw)| bw).reduce(f64::max)
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt
+    }
+
+    fn min_pipe_cwnd(&self) -> usize {
+        BBR_MIN_PIPE_SEGMENTS * self.mss
+    }
+
+    /// ack されたバイト数を数え、ラウンドが終わっていれば帯域を推定する。ラウンドが終わったかどうかを返す。
+    fn update_round(&mut self, acked: usize, now: Duration) -> bool {
+        self.delivered += acked as u64;
+        let round_start = *self.round_start.get_or_insert(now);
+        let delivered = self.delivered - self.round_start_delivered;
+        if delivered < self.round_size {
+            return false;
+        }
+        let elapsed = now - round_start;
+        if !elapsed.is_zero() && self.round_size > 0 {
+            let bw = delivered as f64 / elapsed.as_secs_f64();
+            self.bw_samples.push_back((self.round_count, bw));
+        }
+        self.round_count += 1;
+        while self
+            .bw_samples
+            .front()
+            .is_some_and(|&(round, _)| round + BBR_BTL_BW_FILTER_LEN <= self.round_count)
+        {
+            self.bw_samples.pop_front();
+        }
+        self.round_start = Some(now);
+        self.round_start_delivered = self.delivered;
+        self.round_size = self.cwnd as u64;
+        true
+    }
+
+    fn check_full_pipe(&mut self) {
+        let Some(bw) = self.btl_bw() else {
+            return;
+        };
+        if bw >= self.full_bw * BBR_FULL_BW_THRESH {
+            // まだ帯域が伸びている
+            self.full_bw = bw;
+            self.full_bw_count = 0;
+            return;
+        }
+        self.full_bw_count += 1;
+        self.filled_pipe = self.full_bw_count >= BBR_FULL_BW_COUNT;
+    }
+
+    fn enter_probe_bw(&mut self, now: Duration) {
+        dbg!("bbr: probe bw");
+        self.mode = BbrMode::ProbeBw;
+        self.pacing_gain = BBR_PACING_GAIN_CYCLE[0];
+        self.cwnd_gain = BBR_CWND_GAIN;
+        self.cycle_index = 0;
+        self.cycle_stamp = now;
+    }
+
+    fn update_min_rtt(&mut self, rtt: Option<Duration>, now: Duration) {
+        let expired = self.min_rtt.is_some() && now - self.min_rtt_stamp > BBR_MIN_RTT_FILTER_LEN;
+        if let Some(rtt) = rtt {
+            if expired || self.min_rtt.is_none_or(|min_rtt| rtt <= min_rtt) {
+                self.min_rtt = Some(rtt);
+                self.min_rtt_stamp = now;
+            }
+        }
+        if expired && self.mode != BbrMode::ProbeRtt {
+            // 送信中のデータを減らしてキューを空にし、本来の RTT を測る
+            dbg!("bbr: probe rtt");
+            self.mode = BbrMode::ProbeRtt;
+            self.pacing_gain = 1.0;
+            self.cwnd_gain = 1.0;
+            self.probe_rtt_done = None;
+        }
+    }
+
+    fn update_mode(&mut self, round_ended: bool, now: Duration) {
+        match self.mode {
+            BbrMode::Startup if self.filled_pipe => {
+                // スタートアップで溜めたキューを解消する
+                dbg!("bbr: drain");
+                self.mode = BbrMode::Drain;
+                self.pacing_gain = 1.0 / BBR_HIGH_GAIN;
+            }
+            // 1ラウンドの間 1/HIGH_GAIN 倍で送れば、スタートアップで溜めた分はほぼ解消される
+            BbrMode::Drain if round_ended => self.enter_probe_bw(now),
+            BbrMode::ProbeBw => {
+                // 各位相は最小 RTT だけ続ける。ただし RTT が非常に短いと ACK ごとに位相が変わって
+                // 1.25 倍で探る分の帯域が推定に反映されないので、少なくとも1ラウンドは続ける
+                let phase = self.min_rtt.unwrap_or(BBR_INITIAL_RTT);
+                if round_ended && now - self.cycle_stamp > phase {
+                    self.cycle_index = (self.cycle_index + 1) % BBR_PACING_GAIN_CYCLE.len();
+                    self.cycle_stamp = now;
+                    self.pacing_gain = BBR_PACING_GAIN_CYCLE[self.cycle_index];
+                }
+            }
+            BbrMode::ProbeRtt => {
+                let done = *self
+                    .probe_rtt_done
+                    .get_or_insert(now + BBR_PROBE_RTT_DURATION);
+                if now >= done && round_ended {
+                    self.min_rtt_stamp = now;
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.mode = BbrMode::Startup;
+                        self.pacing_gain = BBR_HIGH_GAIN;
+                        self.cwnd_gain = BBR_HIGH_GAIN;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn update_cwnd(&mut self, acked: usize) {
+        if self.mode == BbrMode::ProbeRtt {
+            self.cwnd = self.min_pipe_cwnd();
+            return;
+        }
+        let target = match (self.btl_bw(), self.min_rtt) {
+            (Some(bw), Some(min_rtt)) => cmp::max(
+                (self.cwnd_gain * bw * min_rtt.as_secs_f64()) as usize,
+                self.min_pipe_cwnd(),
+            ),
+            _ => usize::MAX,
+        };
+        self.cwnd = if self.filled_pipe {
+            cmp::min(self.cwnd + acked, target)
+        } else if self.cwnd < target {
+            // スタートアップ中は、まだ推定値が小さいので減らさずに増やし続ける
+            self.cwnd + acked
+        } else {
+            self.cwnd
+        };
+        self.cwnd = cmp::max(self.cwnd, self.min_pipe_cwnd());
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn on_ack(&mut self, acked: usize, rtt: Option<Duration>, now: Duration) {
+        let round_ended = self.update_round(acked, now);
+        if round_ended && !self.filled_pipe {
+            self.check_full_pipe();
+        }
+        self.update_min_rtt(rtt, now);
+        self.update_mode(round_ended, now);
+        self.update_cwnd(acked);
+    }
+
+    // ロスは帯域の推定に使わないので、何もしない
+    fn on_loss(&mut self, _flight_size: usize, _now: Duration) {}
+
+    fn on_rto(&mut self, _flight_size: usize, _now: Duration) {
+        // 推定したモデルは残したまま、ack されるにつれて目標の cwnd まで戻す
+        self.cwnd = self.mss;
+    }
+
+    fn cwnd(&self) -> usize {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> usize {
+        usize::MAX
+    }
+
+    fn pacing_rate(&self) -> Option<u64> {
+        let bw = self.btl_bw().unwrap_or_else(|| {
+            // まだ帯域を推定していないので、初期ウィンドウを1 RTT で送れる速さにする
+            let rtt = self
+                .min_rtt
+                .filter(|rtt| !rtt.is_zero())
+                .unwrap_or(BBR_INITIAL_RTT);
+            initial_window(self.mss) as f64 / rtt.as_secs_f64()
+        });
+        Some((self.pacing_gain * bw) as u64)
+    }
+}
@@ -0,0 +1,1599 @@
+use crate::congestion::{CongestionAlgorithm, CongestionControl};
+use crate::error::TCPError;
+use crate::packet::{TCPPacket, TCP_HEADER_SIZE};
+use crate::reassembly::ReassemblyQueue;
+use crate::seqnum::SeqNum;
+use crate::socket::{
+    RecvParam, RetransmissionQueueEntry, SendParam, SockID, SocketStats, TcpStatus,
+};
+use crate::tcpflags;
+use crate::tcpoption::{self, TcpOption};
+use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
+use pnet::util;
+use std::cmp;
+use std::collections::VecDeque;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+// 送受信バッファの既定のバイト数
+pub const SOCKET_BUFFER_SIZE: usize = 4380;
+// 送受信バッファの最大のバイト数
+pub const MAX_SOCKET_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+// ウィンドウスケールの最大値。ウィンドウは 2^30 バイトまでになる (RFC 7323)
+const MAX_WINDOW_SHIFT: u8 = 14;
+const IPV4_HEADER_SIZE: usize = 20;
+// Ethernet の MTU 1500 バイトから、IP と TCP のヘッダを引いたもの
+pub const DEFAULT_LOCAL_MSS: usize = 1460;
+// 相手が SYN で MSS オプションを送ってこなかった場合の MSS (RFC 9293)
+pub const DEFAULT_SEND_MSS: usize = 536;
+// MSS の下限。最大 40 バイトのオプションを付けても、ペイロードを載せられるようにする (Linux の TCP_MIN_SND_MSS と同じ)
+pub const MIN_MSS: usize = 48;
+// この数だけ重複 ACK が届いたら、タイムアウトを待たずに再送する (RFC 5681)
+const DUPLICATE_ACK_THRESHOLD: usize = 3;
+// 1つの ACK に載せる SACK ブロックの最大数。オプションフィールドの 40 バイトに収まるのは4つまで (RFC 2018)
+const MAX_SACK_BLOCKS: usize = 4;
+// タイムスタンプオプションも載せる場合は、3つまでしか収まらない
+const MAX_SACK_BLOCKS_WITH_TIMESTAMPS: usize = 3;
+// これより長く更新されていない TS.Recent は、相手のタイムスタンプが一周している可能性があるので PAWS に使わない (RFC 7323)
+const PAWS_IDLE_LIMIT: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+// セグメントがネットワーク上に残りうる最大時間 (Maximum Segment Lifetime)。RFC 9293 では2分とされている。
+pub const DEFAULT_MSL: Duration = Duration::from_secs(120);
+// 送信したデータが ack されないまま、接続を中断するまでに待つ時間。RFC 9293 では5分とされている。
+pub const DEFAULT_USER_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// 1つのコネクションの TCP の状態機械。
+/// スレッドやロック、セグメントの送受信からは独立していて、到着したセグメント・ユーザーの操作・時刻の経過を入力にとり、
+/// 送信すべきセグメントと発生したイベントをキューに積む。呼び出し側は `poll_transmit` と `poll_event` でそれらを取り出す。
+/// 時刻は `Clock::now` と同じく、任意の起点からの経過時間で渡す。
+pub struct Connection {
+    pub local_addr: Ipv4Addr,
+    pub remote_addr: Ipv4Addr,
+    pub local_port: u16,
+    pub remote_port: u16,
+    pub send_param: SendParam,
+    pub recv_param: RecvParam,
+    pub status: TcpStatus,
+
+    // 到着したデータを一度保管する。TCPセグメントは通信の途中で順番が入れ替わったり失われたり色々あるので。
+    // ここには順序通りに揃ったデータだけを置き、先の seq のデータは reassembly_queue で穴が埋まるのを待つ。
+    pub recv_buffer: Vec<u8>,
+    pub reassembly_queue: ReassemblyQueue,
+
+    pub retransmission_queue: VecDeque<RetransmissionQueueEntry>,
+
+    // RST などで異常終了した場合の理由。以降の send/recv はこのエラーを返す。
+    pub error: Option<TCPError>,
+
+    // TIMEWAIT 状態に留まる時間は、この2倍になる
+    pub msl: Duration,
+
+    // 送信したセグメントがこの時間 ack されなければ、相手に到達できないものとして接続を中断する (RFC 5482)
+    pub user_timeout: Duration,
+
+    pub stats: SocketStats,
+
+    // SYN と SYN|ACK で相手に伝える、受信できるセグメントの最大のペイロードサイズ
+    pub local_mss: usize,
+
+    // 送信済みで、まだ ack されていないデータを保持しておけるバイト数。これを超えて送信はしない
+    pub send_buffer_size: usize,
+
+    // SYN と SYN|ACK で、お互いに SACK オプションを使えることを確認したかどうか
+    pub sack_permitted: bool,
+
+    // SYN と SYN|ACK で、お互いにタイムスタンプオプションを使えることを確認したかどうか
+    pub timestamps: bool,
+
+    // 輻輳制御。送信できるデータ量を、受信側のウィンドウに加えて輻輳ウィンドウでも制限する
+    pub congestion_algorithm: CongestionAlgorithm,
+    pub congestion: Box<dyn CongestionControl>,
+
+    // ペーシングで、次のセグメントを送信してよい時刻
+    next_send_time: Duration,
+
+    // TIMEWAIT 状態を抜けて CLOSED になる時刻
+    time_wait_expiry: Duration,
+
+    // 続けて届いた重複 ACK の数
+    duplicate_acks: usize,
+
+    // 高速リカバリ中かどうか。全て ack されればリカバリを終える (RFC 6582)
+    in_recovery: bool,
+
+    // ロスを検出した時点で送信済みだった seq の次。ここまで ack されるまでは、重複 ACK で再び高速再送しない
+    recover: Option<SeqNum>,
+
+    // 相手から最後に届いたセグメントのウィンドウサイズ。重複 ACK の判定に使う
+    peer_window: u32,
+
+    // SACK を使ったリカバリ中に再送した seq の次 (RFC 6675 HighRxt)。これより前のセグメントは再送済み
+    high_rxt: SeqNum,
+
+    // 最後に受信した、順序が揃っていないセグメントの seq。それを含む SACK ブロックを先頭にして伝える
+    last_out_of_order: Option<SeqNum>,
+
+    // 相手から受け取ったタイムスタンプのうち、次に送るセグメントで返すもの (RFC 7323 TS.Recent) と、それを更新した時刻
+    ts_recent: u32,
+    ts_recent_time: Duration,
+
+    // 最後に送信したセグメントの ack (RFC 7323 Last.ACK.sent)
+    last_ack_sent: SeqNum,
+
+    // 最後に広告した受信ウィンドウの右端 (recv_param.next + ウィンドウ)。まだ ACK を送っていなければ None
+    recv_window_edge: Option<SeqNum>,
+
+    // 相手のウィンドウが 0 で送信できないときに、次にウィンドウを問い合わせるプローブを送る時刻と、送った回数
+    window_probe_time: Option<Duration>,
+    window_probe_count: u8,
+
+    // 送信待ちのセグメント
+    transmits: VecDeque<TCPPacket>,
+
+    // 呼び出し側にまだ取り出されていないイベント
+    events: VecDeque<TCPEventKind>,
+}
+
+impl Connection {
+    pub fn new(
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        local_port: u16,
+        remote_port: u16,
+        status: TcpStatus,
+    ) -> Self {
+        let mut connection = Self {
+            local_addr,
+            remote_addr,
+            local_port,
+            remote_port,
+            send_param: SendParam {
+                unacked_seq: SeqNum::default(),
+                initial_seq: SeqNum::default(),
+                next: SeqNum::default(),
+                window: SOCKET_BUFFER_SIZE as u32,
+                wl1: SeqNum::default(),
+                wl2: SeqNum::default(),
+                mss: DEFAULT_SEND_MSS,
+                window_shift: 0,
+            },
+            recv_param: RecvParam {
+                initial_seq: SeqNum::default(),
+                next: SeqNum::default(),
+                window: SOCKET_BUFFER_SIZE as u32,
+                window_shift: window_shift_for(SOCKET_BUFFER_SIZE),
+            },
+            status,
+            recv_buffer: vec![0; SOCKET_BUFFER_SIZE],
+            reassembly_queue: ReassemblyQueue::new(),
+            retransmission_queue: VecDeque::new(),
+            error: None,
+            msl: DEFAULT_MSL,
+            user_timeout: DEFAULT_USER_TIMEOUT,
+            stats: SocketStats::default(),
+            local_mss: DEFAULT_LOCAL_MSS,
+            send_buffer_size: SOCKET_BUFFER_SIZE,
+            sack_permitted: false,
+            timestamps: false,
+            congestion_algorithm: CongestionAlgorithm::default(),
+            congestion: CongestionAlgorithm::default().build(DEFAULT_SEND_MSS),
+            next_send_time: Duration::ZERO,
+            time_wait_expiry: Duration::ZERO,
+            duplicate_acks: 0,
+            in_recovery: false,
+            recover: None,
+            peer_window: 0,
+            high_rxt: SeqNum::default(),
+            last_out_of_order: None,
+            ts_recent: 0,
+            ts_recent_time: Duration::ZERO,
+            last_ack_sent: SeqNum::default(),
+            recv_window_edge: None,
+            window_probe_time: None,
+            window_probe_count: 0,
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        };
+        connection.record_congestion_stats();
+        connection
+    }
+
+    /// MTU のインターフェースから送受信するのに合わせた MSS。MIN_MSS より小さくはしない。
+    pub fn mss_for_mtu(mtu: usize) -> usize {
+        mtu.saturating_sub(IPV4_HEADER_SIZE + TCP_HEADER_SIZE)
+            .clamp(MIN_MSS, u16::MAX as usize)
+    }
+
+    /// アクティブオープン。SYN を送信して SYNSENT 状態のコネクションを返す。
+    pub fn connect(
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
+        local_port: u16,
+        remote_port: u16,
+        initial_seq: SeqNum,
+        now: Duration,
+    ) -> Self {
+        let mut connection = Self::new(
+            local_addr,
+            remote_addr,
+            local_port,
+            remote_port,
+            TcpStatus::SynSent,
+        );
+        connection.open(initial_seq, now);
+        connection
+    }
+
+    /// SYN を送信して、SYNSENT 状態に遷移する。
+    /// SYN に載せる local_mss などの設定は、呼び出す前に済ませておく。
+    pub fn open(&mut self, initial_seq: SeqNum, now: Duration) {
+        self.status = TcpStatus::SynSent;
+        self.send_param.initial_seq = initial_seq;
+        // ここで SYN を送ってる。3 way handshake の最初のセグメント。
+        self.send_tcp_packet(initial_seq, SeqNum::default(), tcpflags::SYN, &[], now);
+        self.send_param.unacked_seq = initial_seq;
+        // NOTE: SYN セグメントはペイロードを持たないが、確認応答を受け取るために1つインクリメントする。FIN セグメントも同様。
+        self.send_param.next = initial_seq + 1;
+    }
+
+    /// LISTEN状態のコネクションに到着したパケットの処理
+    /// SYN であればパッシブオープンして、SYNRCVD 状態の新しいコネクションを返す。
+    pub fn accept(
+        &self,
+        remote_addr: Ipv4Addr,
+        packet: &TCPPacket,
+        initial_seq: SeqNum,
+        now: Duration,
+    ) -> AcceptOutcome {
+        dbg!("listen handler");
+        if packet.get_flag() & tcpflags::RST > 0 {
+            // LISTEN 状態では RST は無視する
+            return AcceptOutcome::Discarded;
+        }
+        if packet.get_flag() & tcpflags::ACK > 0 {
+            // まだ何も送信していないので、どんな ACK も受け入れられない
+            return match reset_segment(self.local_addr, remote_addr, packet) {
+                Some(rst) => AcceptOutcome::Reset(rst),
+                None => AcceptOutcome::Discarded,
+            };
+        }
+        if packet.get_flag() & tcpflags::SYN == 0 {
+            return AcceptOutcome::Discarded;
+        }
+
+        // passive open の処理
+        // 後に接続済みソケットとなるコネクションを新たに生成する
+        let mut connection = Self::new(
+            self.local_addr,
+            remote_addr,
+            self.local_port,
+            packet.get_src(),
+            TcpStatus::SynRcvd,
+        );
+        connection.msl = self.msl;
+        connection.user_timeout = self.user_timeout;
+        connection.local_mss = self.local_mss;
+        connection.send_buffer_size = self.send_buffer_size;
+        connection.set_recv_buffer_size(self.recv_buffer.len());
+        connection.send_param.mss = peer_mss(packet);
+        // 相手も SYN でウィンドウスケールを送ってきた場合にだけ、お互いのウィンドウをスケールする
+        match peer_window_shift(packet) {
+            Some(shift) => connection.send_param.window_shift = shift,
+            None => connection.recv_param.window_shift = 0,
+        }
+        connection.set_congestion_control(self.congestion_algorithm);
+        connection.recv_param.next = packet.get_seq() + 1;
+        connection.recv_param.initial_seq = packet.get_seq();
+        connection.send_param.initial_seq = initial_seq;
+        // SYN のウィンドウはスケールしない
+        connection.set_send_window(packet, packet.get_window_size() as u32);
+        connection.peer_window = packet.get_window_size() as u32;
+        connection.sack_permitted = packet
+            .options()
+            .any(|option| option == TcpOption::SackPermitted);
+        if let Some(value) = timestamp_value(packet) {
+            connection.timestamps = true;
+            connection.ts_recent = value;
+            connection.ts_recent_time = now;
+        }
+        // 応答したメッセージを返している。
+        connection.send_tcp_packet(
+            initial_seq,
+            connection.recv_param.next,
+            tcpflags::SYN | tcpflags::ACK,
+            &[],
+            now,
+        );
+        connection.send_param.next = initial_seq + 1;
+        connection.send_param.unacked_seq = initial_seq;
+        dbg!("status: listen -> ", &connection.status);
+        AcceptOutcome::Accepted(Box::new(connection))
+    }
+
+    /// 到着したセグメントを状態に応じて処理する。
+    pub fn handle_segment(&mut self, packet: &TCPPacket, now: Duration) {
+        if self.is_synchronized() && self.is_old_duplicate(packet, now) {
+            // 受信ウィンドウに収まっていても、seq が一周する前の古いセグメントなので破棄する (RFC 7323 PAWS)
+            dbg!("old duplicate segment");
+            self.stats.dropped_paws += 1;
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::ACK,
+                &[],
+                now,
+            );
+            return;
+        }
+        if self.is_synchronized() && !self.is_acceptable(packet) {
+            self.drop_unacceptable(packet, now);
+            return;
+        }
+        self.update_ts_recent(packet, now);
+        if packet.get_flag() & tcpflags::RST > 0 {
+            self.reset_handler(packet);
+            return;
+        }
+        match self.status {
+            TcpStatus::SynRcvd => self.synrcvd_handler(packet, now),
+            // SYN を受け取ったということなので、応答をする必要がある。
+            TcpStatus::SynSent => self.synsent_handler(packet, now),
+            TcpStatus::Established => self.established_handler(packet, now),
+            TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(packet, now),
+            TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(packet, now),
+            TcpStatus::Closing => self.closing_handler(packet, now),
+            // 相手の FIN を受信済みなので、受け入れ可能なセグメントで処理すべきものはない。
+            // FIN の再送は受信済みの seq なので、drop_unacceptable で処理される。
+            TcpStatus::TimeWait => {}
+            // コネクションが存在しないものとして RST を返す
+            TcpStatus::Closed => self.send_reset(packet),
+            _ => {
+                dbg!("not implemented state");
+            }
+        }
+    }
+
+    /// 再送キューを見て、タイムアウトしているセグメントを再送する。
+    /// ユーザータイムアウトまでに ack されなければ、TimedOut で接続を中断する。
+    /// TIMEWAIT 状態で 2*MSL が経過していれば CLOSED に遷移する。
+    pub fn handle_timeout(&mut self, now: Duration) {
+        if self.status == TcpStatus::TimeWait && now >= self.time_wait_expiry {
+            self.status = TcpStatus::Closed;
+            dbg!("status: timewait ->", &self.status);
+            return;
+        }
+        if self.retransmission_queue.is_empty()
+            && self.window_probe_time.is_some_and(|time| now >= time)
+        {
+            self.send_window_probe(now);
+        }
+        while let Some(mut item) = self.retransmission_queue.pop_front() {
+            // 再送キューから ack されたセグメントを除去する。
+            // established state 以外の時に送信されたセグメントを除去するために必要
+            if self.send_param.unacked_seq >= item.packet.get_seq() + item.packet.get_segment_len()
+            {
+                dbg!("successfully acked", item.packet.get_seq());
+                self.events.push_back(TCPEventKind::Acked);
+                continue;
+            }
+
+            if now - item.first_transmission_time >= self.user_timeout {
+                dbg!("user timeout");
+                self.abort(TCPError::TimedOut);
+                return;
+            }
+
+            // timeout を確認。再送タイムアウトは RTT から計算し、タイムアウトするたびにコネクション全体で2倍にする。
+            if now - item.latest_transmission_time < self.stats.rtt.rto {
+                // timeout していないので再送キューに戻す
+                // この時、これ以降のエントリもタイムアウトしていないと判断できるので、先頭に戻す。
+                self.retransmission_queue.push_front(item);
+                break;
+            }
+
+            // ack されていなければ再送
+            dbg!("retransmit");
+            self.transmits
+                .push_back(self.refresh_options(&item.packet, now));
+            // ssthresh を下げるのは、セグメントが最初にタイムアウトしたときだけにする (RFC 5681 3.1)。
+            // 同じセグメントの2回目以降のタイムアウトでは、既に小さくした送信量からさらに下げることになってしまう。
+            // SYN はまだデータを送っていないので、輻輳ウィンドウを変えない
+            let first_timeout =
+                item.transmission_count == 1 && item.packet.get_flag() & tcpflags::SYN == 0;
+            item.transmission_count = item.transmission_count.saturating_add(1);
+            item.latest_transmission_time = now;
+            self.stats.retransmissions += 1;
+            self.stats.rtt.on_timeout();
+            // キューを seq の順に保つため、先頭に戻す
+            self.retransmission_queue.push_front(item);
+            // 受信側は SACK したデータを捨てることもできるので、タイムアウトしたら SACK の情報は使わない (RFC 2018)
+            for item in self.retransmission_queue.iter_mut() {
+                item.sacked = false;
+            }
+            if first_timeout {
+                self.congestion.on_rto(self.flight_size(), now);
+                self.record_congestion_stats();
+            }
+            // タイムアウト前に送信したセグメントに対する重複 ACK で、もう一度高速再送しないようにする
+            self.duplicate_acks = 0;
+            self.in_recovery = false;
+            self.recover = Some(self.send_param.next);
+            break;
+        }
+    }
+
+    /// data の先頭から送信ウィンドウに収まる分をセグメントにして送信し、送信したバイト数を返す。
+    /// まだ ack されていなくても送信済みとして数える。
+    /// 輻輳制御がペーシングする場合は、前のセグメントから送信レート分の間隔が空くまで次のセグメントを送らない。
+    pub fn send(&mut self, data: &[u8], now: Duration) -> Result<usize, TCPError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let mut cursor = 0;
+        while cursor < data.len() {
+            // 輻輳ウィンドウのうち、まだ ack されていないデータが占めていない分
+            // SACK を使ったリカバリ中は、SACK されたデータと失われたデータを除いて数える
+            let in_flight = if self.in_recovery && self.sack_permitted {
+                self.pipe()
+            } else {
+                self.flight_size()
+            };
+            let congestion_window = self.congestion.cwnd().saturating_sub(in_flight);
+            let send_size = [
+                self.max_payload_size(now),
+                self.usable_window(),
+                congestion_window,
+                // 送信バッファに、ack されるまでデータを保持しておける分
+                self.send_buffer_size.saturating_sub(self.flight_size()),
+                data.len() - cursor,
+            ]
+            .into_iter()
+            .min()
+            .unwrap();
+            if send_size == 0 {
+                dbg!("unable to slide send window");
+                if self.send_param.window == 0 && self.window_probe_time.is_none() {
+                    // 相手のウィンドウが開いたことを伝える ACK が失われても止まらないように、プローブを送って問い合わせる
+                    self.window_probe_time = Some(now + self.stats.rtt.rto);
+                    self.window_probe_count = 0;
+                }
+                break;
+            }
+            if !self.pacing_delay(now).is_zero() {
+                break;
+            }
+            if let Some(rate) = self.congestion.pacing_rate() {
+                // 送信レートで send_size バイト送るのにかかる時間だけ、次の送信を遅らせる
+                let interval = Duration::from_secs_f64(send_size as f64 / rate.max(1) as f64);
+                self.next_send_time = cmp::max(self.next_send_time, now) + interval;
+            }
+            dbg!("current window size", self.send_param.window);
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::ACK, // 接続済みの場合はずっと ACK フラグは立てておくのか。
+                &data[cursor..cursor + send_size],
+                now,
+            );
+            cursor += send_size;
+            self.send_param.next += send_size as u32;
+        }
+        Ok(cursor)
+    }
+
+    /// ペーシングで、次のセグメントを送信できるようになるまでの時間。ペーシングしていなければ 0 を返す。
+    pub fn pacing_delay(&self, now: Duration) -> Duration {
+        match self.congestion.pacing_rate() {
+            Some(_) => self.next_send_time.saturating_sub(now),
+            None => Duration::ZERO,
+        }
+    }
+
+    /// 送信するセグメントのペイロードの最大サイズ。オプションを含めずに数える (RFC 9293 3.7.1)。
+    /// 相手の MSS と、自分のインターフェースから送れる大きさの小さい方になる。
+    pub fn send_mss(&self) -> usize {
+        cmp::max(cmp::min(self.send_param.mss, self.local_mss), MIN_MSS)
+    }
+
+    /// データセグメントに載せられるペイロードのバイト数。SACK などのオプションを付ける分だけ send_mss より小さくなる。
+    fn max_payload_size(&self, now: Duration) -> usize {
+        let options_size = tcpoption::build(&self.options(tcpflags::ACK, now)).len();
+        self.send_mss().saturating_sub(options_size)
+    }
+
+    /// 輻輳制御アルゴリズムを切り替える。アルゴリズムの状態は初期化される。
+    pub fn set_congestion_control(&mut self, algorithm: CongestionAlgorithm) {
+        self.congestion_algorithm = algorithm;
+        self.congestion = algorithm.build(self.send_mss());
+        self.record_congestion_stats();
+    }
+
+    /// 受信バッファのデータを buffer に読み込んで、読み込んだサイズを返す。
+    /// 読み込めるデータがない場合は、FINを受信済みなら Some(0) を、まだデータが届く可能性があるなら None を返す。
+    /// 異常終了したコネクションは、受信バッファに残っているデータがあってもエラーを返す。
+    /// 読み込んで受信ウィンドウが十分に開いたら、それを相手に ACK で伝える。
+    pub fn recv(&mut self, buffer: &mut [u8], now: Duration) -> Result<Option<usize>, TCPError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let received_size = self.recv_buffer.len() - self.recv_param.window as usize;
+        if received_size == 0 {
+            return Ok(match self.status {
+                TcpStatus::CloseWait
+                | TcpStatus::LastAck
+                | TcpStatus::Closing
+                | TcpStatus::TimeWait
+                | TcpStatus::Closed => Some(0),
+                _ => None,
+            });
+        }
+        let copy_size = cmp::min(buffer.len(), received_size);
+
+        // バッファーにデータを読み込む！
+        buffer[..copy_size].copy_from_slice(&self.recv_buffer[..copy_size]);
+
+        // 読み込まなかった残りの分を先頭に移動させる。
+        self.recv_buffer.copy_within(copy_size.., 0);
+        self.recv_param.window += copy_size as u32;
+        // FIN を受け取った後は、もうデータは届かないので伝えなくてよい
+        let peer_may_send = matches!(
+            self.status,
+            TcpStatus::Established | TcpStatus::FinWait1 | TcpStatus::FinWait2
+        );
+        if peer_may_send
+            && self
+                .recv_window_edge
+                .is_some_and(|edge| self.recv_param.next + self.receive_window() > edge)
+        {
+            dbg!("window update", self.recv_param.window);
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::ACK,
+                &[],
+                now,
+            );
+        }
+        Ok(Some(copy_size))
+    }
+
+    /// FIN を送信して接続を閉じ始める。
+    pub fn close(&mut self, now: Duration) {
+        let next_status = match self.status {
+            TcpStatus::Established => TcpStatus::FinWait1,
+            TcpStatus::CloseWait => TcpStatus::LastAck,
+            _ => return,
+        };
+        self.send_tcp_packet(
+            self.send_param.next,
+            self.recv_param.next,
+            // 注意：最初に送信するときも、ACKフラグは立てておく必要がある。ここは今までと変わらず。
+            tcpflags::FIN | tcpflags::ACK,
+            &[],
+            now,
+        );
+        self.send_param.next += 1;
+        self.status = next_status;
+    }
+
+    /// 送信待ちのセグメントを1つ取り出す。宛先は `remote_addr`。
+    pub fn poll_transmit(&mut self) -> Option<TCPPacket> {
+        self.transmits.pop_front()
+    }
+
+    /// 発生したイベントを1つ取り出す。
+    pub fn poll_event(&mut self) -> Option<TCPEventKind> {
+        self.events.pop_front()
+    }
+
+    pub fn get_sock_id(&self) -> SockID {
+        SockID(
+            self.local_addr,
+            self.remote_addr,
+            self.local_port,
+            self.remote_port,
+        )
+    }
+
+    fn send_tcp_packet(
+        &mut self,
+        seq: SeqNum,
+        ack: SeqNum,
+        flag: u8,
+        payload: &[u8],
+        now: Duration,
+    ) {
+        let mut tcp_packet = TCPPacket::new(payload.len());
+        tcp_packet.set_src(self.local_port);
+        tcp_packet.set_dest(self.remote_port);
+        tcp_packet.set_seq(seq);
+        tcp_packet.set_ack(ack);
+        // NOTE: オプションの分だけヘッダーが伸び、data offset もその分大きくなる。詳しくは[RFC9293](https://datatracker.ietf.org/doc/html/rfc9293)を参照。
+        tcp_packet.set_options(&self.options(flag, now));
+        tcp_packet.set_flag(flag);
+        let window = self.advertised_window(flag);
+        tcp_packet.set_window_size(window);
+        tcp_packet.set_payload(payload);
+        tcp_packet.set_checksum(self.checksum(&tcp_packet));
+        self.transmits.push_back(tcp_packet.clone());
+        if flag & tcpflags::ACK > 0 {
+            self.last_ack_sent = ack;
+            self.recv_window_edge = Some(if flag & tcpflags::SYN > 0 {
+                ack + window as u32
+            } else {
+                ack + ((window as u32) << self.recv_param.window_shift)
+            });
+        }
+
+        // もし送信先から確認応答がこなかった場合は再送する必要がある。
+        // なので、送信直後のこのタイミングでエンキューする。
+        // ただし、ペイロードを持たないACKセグメントは再送対象にはなりません。ACKセグメントのACKセグメントというように、無限に確認応答が必要になる。
+        // 再送対象になるのは、ペイロードが存在しているか、ACKセグメントでないセグメントです。
+        // 例：SYNセグメント、SYN|ACKセグメント、ペイロードをのせたACKセグメント
+        if payload.is_empty() && tcp_packet.get_flag() == tcpflags::ACK {
+            return;
+        }
+        self.retransmission_queue
+            .push_back(RetransmissionQueueEntry::new(tcp_packet, now));
+    }
+
+    /// 受信バッファの大きさを変える。ウィンドウスケールを伝える SYN を送る前に呼ぶこと。
+    /// MAX_SOCKET_BUFFER_SIZE より大きくはできない。
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        let size = cmp::min(size, MAX_SOCKET_BUFFER_SIZE);
+        self.recv_buffer = vec![0; size];
+        self.recv_param.window = size as u32;
+        self.recv_param.window_shift = window_shift_for(size);
+    }
+
+    /// ウィンドウスケールを使っているかどうか。相手が SYN で伝えてこなかった場合は、recv_param.window_shift を 0 にしている。
+    /// お互いに 0 を伝え合った場合は、使っていないのと同じなので false になる。
+    fn window_scaling(&self) -> bool {
+        self.send_param.window_shift > 0 || self.recv_param.window_shift > 0
+    }
+
+    /// flag のセグメントで相手に広告するウィンドウ。SYN 以外では、ウィンドウスケールの分だけ右シフトする (RFC 7323)。
+    fn advertised_window(&self, flag: u8) -> u16 {
+        let window = if flag & tcpflags::SYN > 0 {
+            self.recv_param.window
+        } else {
+            self.receive_window() >> self.recv_param.window_shift
+        };
+        cmp::min(window, u16::MAX as u32) as u16
+    }
+
+    /// 相手に広告する受信ウィンドウ (RFC 9293 3.8.6.2.2)。
+    /// 少しずつ読み込まれるたびに小さなウィンドウを広告すると、相手が小さなセグメントばかり送るようになる (silly window syndrome)。
+    /// なので、右端は受信バッファの半分か MSS の小さい方以上に開くまで、前回広告した位置に留める。
+    fn receive_window(&self) -> u32 {
+        let next = self.recv_param.next;
+        let edge = next + self.recv_param.window;
+        let threshold = cmp::min(self.recv_buffer.len() / 2, self.local_mss) as u32;
+        match self.recv_window_edge {
+            Some(advertised)
+                if next <= advertised && advertised <= edge && edge - advertised < threshold =>
+            {
+                advertised - next
+            }
+            _ => self.recv_param.window,
+        }
+    }
+
+    /// 到着したセグメントが広告しているウィンドウ。SYN 以外では、ウィンドウスケールの分だけ左シフトする。
+    fn received_window(&self, packet: &TCPPacket) -> u32 {
+        let window = packet.get_window_size() as u32;
+        if packet.get_flag() & tcpflags::SYN > 0 {
+            window
+        } else {
+            window << self.send_param.window_shift
+        }
+    }
+
+    /// now に送信する、flag のセグメントに付けるオプション
+    fn options(&self, flag: u8, now: Duration) -> Vec<TcpOption> {
+        let mut options = Vec::new();
+        if flag & tcpflags::SYN > 0 {
+            options.push(TcpOption::Mss(self.local_mss as u16));
+            // SYN|ACK では、相手も送ってきた場合にだけ伝える
+            if flag & tcpflags::ACK == 0 || self.window_scaling() {
+                options.push(TcpOption::WindowScale(self.recv_param.window_shift));
+            }
+            // SYN では常に SACK を使えることを伝え、SYN|ACK では相手も使える場合にだけ伝える
+            if flag & tcpflags::ACK == 0 || self.sack_permitted {
+                options.push(TcpOption::SackPermitted);
+            }
+        } else if self.sack_permitted {
+            let blocks = self.sack_blocks();
+            if !blocks.is_empty() {
+                options.push(TcpOption::Sack(blocks));
+            }
+        }
+        // タイムスタンプは、SYN では常に付けて、それ以降は相手も使える場合にだけ全てのセグメントに付ける
+        if flag & tcpflags::SYN > 0 && flag & tcpflags::ACK == 0 || self.timestamps {
+            options.push(TcpOption::Timestamps {
+                value: timestamp(now),
+                // ACK が立っていないセグメントの TSecr は使われないので 0 にする
+                echo_reply: if flag & tcpflags::ACK > 0 {
+                    self.ts_recent
+                } else {
+                    0
+                },
+            });
+        }
+        options
+    }
+
+    /// 再送するセグメント。送信したときの SACK ブロックは古くなっているので取り除く。
+    /// タイムスタンプを付けていれば、今の時刻と TS.Recent に付け替えて、再送したセグメントの ACK からも RTT を計測できるようにする (RFC 7323)。
+    fn refresh_options(&self, packet: &TCPPacket, now: Duration) -> TCPPacket {
+        let mut packet = packet.clone();
+        if !packet
+            .options()
+            .any(|option| matches!(option, TcpOption::Sack(_) | TcpOption::Timestamps { .. }))
+        {
+            return packet;
+        }
+        let options: Vec<_> = packet
+            .options()
+            .filter(|option| !matches!(option, TcpOption::Sack(_)))
+            .map(|option| match option {
+                TcpOption::Timestamps { echo_reply, .. } => TcpOption::Timestamps {
+                    value: timestamp(now),
+                    echo_reply: if packet.get_flag() & tcpflags::ACK > 0 {
+                        self.ts_recent
+                    } else {
+                        echo_reply
+                    },
+                },
+                option => option,
+            })
+            .collect();
+        packet.set_options(&options);
+        packet.set_checksum(self.checksum(&packet));
+        packet
+    }
+
+    /// セグメントのチェックサム。packet のチェックサムフィールドは計算に含めない。
+    fn checksum(&self, packet: &TCPPacket) -> u16 {
+        util::ipv4_checksum(
+            packet.packet(),
+            8,
+            &[],
+            &self.local_addr,
+            &self.remote_addr,
+            IpNextHeaderProtocols::Tcp,
+        )
+    }
+
+    /// タイムスタンプを使っていて、相手のタイムスタンプが TS.Recent より古いセグメントかどうか (RFC 7323 PAWS)。
+    /// RST は、タイムスタンプに関わらず処理する。
+    fn is_old_duplicate(&self, packet: &TCPPacket, now: Duration) -> bool {
+        if !self.timestamps
+            || packet.get_flag() & tcpflags::RST > 0
+            || now - self.ts_recent_time > PAWS_IDLE_LIMIT
+        {
+            return false;
+        }
+        timestamp_value(packet).is_some_and(|value| timestamp_before(value, self.ts_recent))
+    }
+
+    /// 受け入れたセグメントのタイムスタンプを、次に送るセグメントで返すように TS.Recent に記録する (RFC 7323)。
+    /// 最後に送った ACK までのセグメントに限るので、手前が欠けたセグメントのタイムスタンプは返さない。
+    fn update_ts_recent(&mut self, packet: &TCPPacket, now: Duration) {
+        if !self.timestamps || packet.get_seq() > self.last_ack_sent {
+            return;
+        }
+        if let Some(value) = timestamp_value(packet) {
+            if !timestamp_before(value, self.ts_recent)
+                || now - self.ts_recent_time > PAWS_IDLE_LIMIT
+            {
+                self.ts_recent = value;
+                self.ts_recent_time = now;
+            }
+        }
+    }
+
+    /// ACK のタイムスタンプが返してきた、自分が送信したときの時刻から RTT を計測する (RFC 7323 RTTM)。
+    fn echoed_rtt(&self, packet: &TCPPacket, now: Duration) -> Option<Duration> {
+        if !self.timestamps || packet.get_flag() & tcpflags::ACK == 0 {
+            return None;
+        }
+        let echo_reply = packet.options().find_map(|option| match option {
+            TcpOption::Timestamps { echo_reply, .. } => Some(echo_reply),
+            _ => None,
+        })?;
+        let rtt = timestamp(now).wrapping_sub(echo_reply);
+        // 送信していない未来のタイムスタンプは無視する
+        (rtt as i32 >= 0).then(|| Duration::from_millis(rtt as u64))
+    }
+
+    /// 受信済みで、順序が揃うのを待っているデータのブロック (RFC 2018)。
+    /// 最後に受信したセグメントを含むブロックを先頭にして、残りは seq の順に並べる。
+    fn sack_blocks(&self) -> Vec<(SeqNum, SeqNum)> {
+        let mut blocks: Vec<_> = self.reassembly_queue.ranges().collect();
+        if let Some(seq) = self.last_out_of_order {
+            if let Some(i) = blocks
+                .iter()
+                .position(|&(left, right)| left <= seq && seq < right)
+            {
+                let block = blocks.remove(i);
+                blocks.insert(0, block);
+            }
+        }
+        blocks.truncate(if self.timestamps {
+            MAX_SACK_BLOCKS_WITH_TIMESTAMPS
+        } else {
+            MAX_SACK_BLOCKS
+        });
+        blocks
+    }
+
+    /// RST セグメントの処理
+    /// 正当な RST であればコネクションを破棄し、通信中であれば異常終了として呼び出し側にエラーを通知する。
+    fn reset_handler(&mut self, packet: &TCPPacket) {
+        dbg!("reset handler");
+        let acceptable = match self.status {
+            // SYNSENT では、送信した SYN を ack している RST のみ受け入れる
+            TcpStatus::SynSent => {
+                packet.get_flag() & tcpflags::ACK > 0
+                    && self.send_param.initial_seq < packet.get_ack()
+                    && packet.get_ack() <= self.send_param.next
+            }
+            // TIMEWAIT で RST を受け入れると、2*MSL 待つ前にコネクションが破棄されてしまう (RFC 1337)
+            TcpStatus::Listen | TcpStatus::TimeWait | TcpStatus::Closed => false,
+            // それ以外では、seq が受信ウィンドウ内にある RST のみ受け入れる。
+            // ウィンドウ外の RST は、古いコネクションの重複セグメントや第三者による偽造の可能性があるため破棄する。
+            _ => {
+                let offset = packet.get_seq() - self.recv_param.next;
+                offset < cmp::max(self.recv_param.window, 1)
+            }
+        };
+        if !acceptable {
+            dbg!("unacceptable reset");
+            return;
+        }
+
+        let error = match self.status {
+            TcpStatus::SynSent | TcpStatus::SynRcvd => Some(TCPError::ConnectionRefused),
+            TcpStatus::Established
+            | TcpStatus::FinWait1
+            | TcpStatus::FinWait2
+            | TcpStatus::CloseWait => Some(TCPError::ConnectionReset),
+            // 自分も FIN を送信済みで、相手からも FIN を受け取っているので、正常に閉じたものとして扱う
+            _ => None,
+        };
+        dbg!("status: reset ->", &self.status);
+        match error {
+            Some(error) => self.abort(error),
+            None => {
+                self.status = TcpStatus::Closed;
+                self.retransmission_queue.clear();
+                self.events.push_back(TCPEventKind::ConnectionClosed);
+            }
+        }
+    }
+
+    /// コネクションを異常終了させる。以降の send/recv は error を返す。
+    fn abort(&mut self, error: TCPError) {
+        self.status = TcpStatus::Closed;
+        self.retransmission_queue.clear();
+        self.error = Some(error);
+        self.events.push_back(TCPEventKind::ConnectionAborted);
+    }
+
+    /// 受け入れられないセグメントに対して RST を送信する。RST は再送しない。
+    fn send_reset(&mut self, packet: &TCPPacket) {
+        if let Some(rst) = reset_segment(self.local_addr, self.remote_addr, packet) {
+            dbg!("send reset");
+            self.transmits.push_back(rst);
+        }
+    }
+
+    /// 到着したセグメントの ACK を処理する。未送信のセグメントに対する ACK であれば false を返す。
+    fn process_ack(&mut self, packet: &TCPPacket, now: Duration) -> bool {
+        if self.sack_permitted {
+            self.update_scoreboard(packet);
+        }
+        if self.send_param.unacked_seq < packet.get_ack()
+            && packet.get_ack() <= self.send_param.next
+        {
+            let acked = packet.get_ack() - self.send_param.unacked_seq;
+            self.send_param.unacked_seq = packet.get_ack();
+            let rtt = self.delete_acked_segment_from_retransmission_queue(packet, now);
+            self.duplicate_acks = 0;
+            if !self.in_recovery {
+                self.congestion.on_ack(acked as usize, rtt, now);
+                self.record_congestion_stats();
+            } else if self
+                .recover
+                .is_some_and(|recover| packet.get_ack() < recover)
+            {
+                // partial ACK: ロスを検出した時点で送信済みのセグメントが、まだ他にも失われている
+                if self.sack_permitted {
+                    self.retransmit_lost_segments(now);
+                } else {
+                    self.retransmit_first_unacked(now);
+                    self.congestion.on_partial_ack(acked as usize, now);
+                    self.record_congestion_stats();
+                }
+            } else {
+                dbg!("exit fast recovery");
+                self.in_recovery = false;
+                self.congestion.on_recovery_end(now);
+                self.record_congestion_stats();
+            }
+        } else if self.is_duplicate_ack(packet) {
+            self.duplicate_acks += 1;
+            dbg!("duplicate ack", self.duplicate_acks);
+            if !self.in_recovery
+                && (self.duplicate_acks == DUPLICATE_ACK_THRESHOLD
+                    || self.sack_permitted && self.is_lost(0))
+                && self
+                    .recover
+                    .is_none_or(|recover| packet.get_ack() >= recover)
+            {
+                dbg!("enter fast recovery");
+                self.in_recovery = true;
+                self.recover = Some(self.send_param.next);
+                self.congestion.on_loss(self.flight_size(), now);
+                if !self.sack_permitted {
+                    // 閾値までの重複 ACK の分、相手に届いたセグメントがネットワークから出ていっている
+                    for _ in 0..self.duplicate_acks {
+                        self.congestion.on_duplicate_ack(now);
+                    }
+                }
+                self.record_congestion_stats();
+                self.retransmit_first_unacked(now);
+                if self.sack_permitted {
+                    self.retransmit_lost_segments(now);
+                }
+            } else if self.in_recovery && self.sack_permitted {
+                // 重複 ACK で SACK されたセグメントが増え、他にも失われたとみなせるセグメントがあれば再送する
+                self.retransmit_lost_segments(now);
+            } else if self.in_recovery {
+                // SACK がなければ、広げたウィンドウで新しいセグメントを送って ACK が途切れないようにする
+                self.congestion.on_duplicate_ack(now);
+                self.record_congestion_stats();
+                if self.congestion.cwnd() > self.flight_size() {
+                    self.events.push_back(TCPEventKind::Acked);
+                }
+            }
+        } else if self.send_param.next < packet.get_ack() {
+            // 未送信セグメントに対するackは破棄し、正しい seq を伝えるために ACK を返す
+            dbg!("ack for unsent segment");
+            self.stats.dropped_bad_ack += 1;
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::ACK,
+                &[],
+                now,
+            );
+            return false;
+        }
+        if self.send_param.unacked_seq <= packet.get_ack()
+            && packet.get_ack() <= self.send_param.next
+        {
+            self.update_send_window(packet);
+        }
+        self.peer_window = self.received_window(packet);
+        true
+    }
+
+    /// ウィンドウが 0 の相手に、ack 済みの seq の ACK を送る。相手は受信ウィンドウ外のセグメントとして、今のウィンドウを ACK で返してくる。
+    /// 再送タイムアウトと同じように、送るたびに間隔を2倍にする (RFC 9293 3.8.6.1)。
+    fn send_window_probe(&mut self, now: Duration) {
+        dbg!("window probe");
+        self.send_tcp_packet(
+            self.send_param.unacked_seq - 1,
+            self.recv_param.next,
+            tcpflags::ACK,
+            &[],
+            now,
+        );
+        self.window_probe_count = self.window_probe_count.saturating_add(1);
+        self.window_probe_time = Some(
+            now + self
+                .stats
+                .rtt
+                .backoff(self.window_probe_count.saturating_add(1)),
+        );
+        self.stats.window_probes += 1;
+    }
+
+    /// 送信ウィンドウのうち、まだ送信していない部分のバイト数
+    fn usable_window(&self) -> usize {
+        let in_flight = self.send_param.next - self.send_param.unacked_seq;
+        self.send_param.window.saturating_sub(in_flight) as usize
+    }
+
+    /// ack を受け入れたセグメントの広告しているウィンドウで、送信ウィンドウを更新する (RFC 9293 3.10.7.4)。
+    /// 並び替わって届いた古いセグメントのウィンドウで上書きしないように、seq と ack が前回の更新以降のものに限る。
+    fn update_send_window(&mut self, packet: &TCPPacket) {
+        let wl1 = self.send_param.wl1;
+        if wl1 < packet.get_seq()
+            || wl1 == packet.get_seq() && self.send_param.wl2 <= packet.get_ack()
+        {
+            let usable_window = self.usable_window();
+            self.set_send_window(packet, self.received_window(packet));
+            if self.usable_window() > usable_window {
+                // ack が進まなくても送信できるようになったので、送信を待っている呼び出しを起こす
+                self.events.push_back(TCPEventKind::Acked);
+            }
+        }
+    }
+
+    fn set_send_window(&mut self, packet: &TCPPacket, window: u32) {
+        self.send_param.window = window;
+        self.send_param.wl1 = packet.get_seq();
+        self.send_param.wl2 = packet.get_ack();
+        if window > 0 {
+            self.window_probe_time = None;
+        }
+    }
+
+    /// 送信済みのセグメントが ack されないまま、受信側に後続のセグメントが届いたことを示す ACK かどうか (RFC 5681)。
+    /// ウィンドウの大きさが変わっている ACK は、ウィンドウの更新を伝えるためのものなので数えない。
+    fn is_duplicate_ack(&self, packet: &TCPPacket) -> bool {
+        packet.get_flag() & tcpflags::ACK > 0
+            && packet.get_ack() == self.send_param.unacked_seq
+            && packet.get_segment_len() == 0
+            && self.received_window(packet) == self.peer_window
+            && !self.retransmission_queue.is_empty()
+    }
+
+    /// 再送キューの先頭のセグメントを、タイムアウトを待たずに再送する。
+    fn retransmit_first_unacked(&mut self, now: Duration) {
+        if !self.retransmission_queue.is_empty() {
+            self.fast_retransmit(0, now);
+        }
+    }
+
+    /// 再送キューの index 番目のセグメントを、タイムアウトを待たずに再送する。
+    fn fast_retransmit(&mut self, index: usize, now: Duration) {
+        let packet = self.refresh_options(&self.retransmission_queue[index].packet, now);
+        self.transmits.push_back(packet);
+        let item = &mut self.retransmission_queue[index];
+        dbg!("fast retransmit", item.packet.get_seq());
+        item.transmission_count = item.transmission_count.saturating_add(1);
+        item.latest_transmission_time = now;
+        self.high_rxt = item.packet.get_seq() + item.packet.get_segment_len();
+        self.stats.fast_retransmissions += 1;
+    }
+
+    /// SACK オプションで伝えられたブロックに含まれるセグメントを、再送キューで SACK 済みにする。
+    fn update_scoreboard(&mut self, packet: &TCPPacket) {
+        for option in packet.options() {
+            let TcpOption::Sack(blocks) = option else {
+                continue;
+            };
+            for (left, right) in blocks {
+                // 送信していない範囲を含むブロックは無視する
+                if !(self.send_param.unacked_seq <= left
+                    && left < right
+                    && right <= self.send_param.next)
+                {
+                    continue;
+                }
+                for item in self.retransmission_queue.iter_mut() {
+                    let seq = item.packet.get_seq();
+                    if left <= seq && seq + item.packet.get_segment_len() <= right {
+                        item.sacked = true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// 再送キューの index 番目のセグメントが失われたとみなせるか (RFC 6675 IsLost)。
+    /// 後ろのセグメントが重複 ACK の閾値の数以上 SACK されているか、(閾値 - 1) * MSS バイトより多く SACK されていれば失われている。
+    fn is_lost(&self, index: usize) -> bool {
+        let (count, bytes) = self
+            .retransmission_queue
+            .iter()
+            .skip(index + 1)
+            .filter(|item| item.sacked)
+            .fold((0, 0), |(count, bytes), item| {
+                (count + 1, bytes + item.packet.payload().len())
+            });
+        count >= DUPLICATE_ACK_THRESHOLD || bytes > (DUPLICATE_ACK_THRESHOLD - 1) * self.send_mss()
+    }
+
+    /// ネットワーク上に残っていると推定されるバイト数 (RFC 6675 SetPipe)。
+    /// SACK されたセグメントと失われたとみなせるセグメントは数えず、リカバリ中に再送したセグメントは再送した分を数える。
+    fn pipe(&self) -> usize {
+        self.retransmission_queue
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| !item.sacked)
+            .map(|(i, item)| {
+                let len = item.packet.get_segment_len() as usize;
+                let mut pipe = if self.is_lost(i) { 0 } else { len };
+                if item.packet.get_seq() < self.high_rxt {
+                    pipe += len;
+                }
+                pipe
+            })
+            .sum()
+    }
+
+    /// リカバリ中、輻輳ウィンドウに1セグメント分の空きがある間、失われたとみなせるセグメントを先頭から再送する (RFC 6675 NextSeg)。
+    /// 一度のリカバリで、同じセグメントは2回再送しない。
+    fn retransmit_lost_segments(&mut self, now: Duration) {
+        while self.pipe() + self.send_mss() <= self.congestion.cwnd() {
+            let next = (0..self.retransmission_queue.len()).find(|&i| {
+                let item = &self.retransmission_queue[i];
+                !item.sacked && item.packet.get_seq() >= self.high_rxt && self.is_lost(i)
+            });
+            match next {
+                Some(i) => self.fast_retransmit(i, now),
+                None => break,
+            }
+        }
+    }
+
+    /// 送信済みで、まだ ack されていないバイト数
+    fn flight_size(&self) -> usize {
+        (self.send_param.next - self.send_param.unacked_seq) as usize
+    }
+
+    /// 輻輳制御の状態を統計情報に反映する。
+    fn record_congestion_stats(&mut self) {
+        self.stats.cwnd = self.congestion.cwnd();
+        self.stats.ssthresh = self.congestion.ssthresh();
+        self.stats.pacing_rate = self.congestion.pacing_rate();
+    }
+
+    /// 相手の SYN を受け取って、受信する seq が決まった状態かどうか。
+    /// これらの状態では、到着したセグメントの seq を受信ウィンドウで検査する (RFC 9293 3.10.7.4 では SYNRCVD から検査する)。
+    fn is_synchronized(&self) -> bool {
+        matches!(
+            self.status,
+            TcpStatus::SynRcvd
+                | TcpStatus::Established
+                | TcpStatus::FinWait1
+                | TcpStatus::FinWait2
+                | TcpStatus::CloseWait
+                | TcpStatus::Closing
+                | TcpStatus::LastAck
+                | TcpStatus::TimeWait
+        )
+    }
+
+    /// セグメントの seq が受信ウィンドウと重なっているかを検査する (RFC 9293 3.10.7.4)。
+    /// ウィンドウから一部はみ出しているセグメントも受け入れ、はみ出した部分は process_payload で切り捨てる。
+    fn is_acceptable(&self, packet: &TCPPacket) -> bool {
+        let seq = packet.get_seq();
+        let len = packet.get_segment_len();
+        let next = self.recv_param.next;
+        let window = self.recv_param.window;
+        let in_window = |seq: SeqNum| next <= seq && seq < next + window;
+        match (len, window) {
+            (0, 0) => seq == next,
+            (0, _) => in_window(seq),
+            // ウィンドウが 0 でも ACK や RST は処理する必要があるので、seq がちょうど次に受信する位置であれば受け入れる。
+            // ペイロードは全て切り捨てられる。
+            (_, 0) => seq == next,
+            (_, _) => in_window(seq) || in_window(seq + (len - 1)),
+        }
+    }
+
+    /// 受信ウィンドウ外のセグメントを破棄する。
+    /// 再送されてきた受信済みのセグメントなどなので、次に受信したい seq を ACK で伝え直す。
+    fn drop_unacceptable(&mut self, packet: &TCPPacket, now: Duration) {
+        dbg!("unacceptable segment");
+        self.stats.dropped_out_of_window += 1;
+        if packet.get_flag() & tcpflags::RST > 0 {
+            // RST に応答はしない
+            return;
+        }
+        if self.status == TcpStatus::TimeWait && packet.get_flag() & tcpflags::FIN > 0 {
+            // FIN が再送されてきたのは、送信した ACK が失われたため。2*MSL のタイマーをやり直す。
+            self.time_wait_expiry = now + self.msl * 2;
+        }
+        self.send_tcp_packet(
+            self.send_param.next,
+            self.recv_param.next,
+            tcpflags::ACK,
+            &[],
+            now,
+        );
+    }
+
+    /// 相手の FIN に ACK を返したあと、送信した FIN も ack されていれば TIMEWAIT に遷移する。
+    fn enter_time_wait(&mut self, now: Duration) {
+        // 送信した ACK が失われた場合に、相手が再送してくる FIN に応答するため、すぐには閉じずに TIMEWAIT で待つ。
+        // また、同じ SockID の新しいコネクションに、このコネクションの遅れて届いたセグメントが紛れ込まないようにする。
+        self.status = TcpStatus::TimeWait;
+        self.time_wait_expiry = now + self.msl * 2;
+        dbg!("status: -> ", &self.status);
+        self.events.push_back(TCPEventKind::ConnectionClosed);
+    }
+
+    /// packet で ack されたセグメントを再送キューから取り除き、計測できた RTT を返す。
+    /// タイムスタンプを使っていれば、再送したセグメントの ACK でも再送タイムアウトのための RTT を計測する。
+    fn delete_acked_segment_from_retransmission_queue(
+        &mut self,
+        packet: &TCPPacket,
+        now: Duration,
+    ) -> Option<Duration> {
+        dbg!("ack accept", self.send_param.unacked_seq);
+        // ack されたうち最後に送信したセグメントで RTT を計測する
+        let mut rtt = None;
+        while let Some(item) = self.retransmission_queue.pop_front() {
+            // セグメントの一部だけが ack された場合は、残りを再送するためにキューに残す
+            if self.send_param.unacked_seq >= item.packet.get_seq() + item.packet.get_segment_len()
+            {
+                dbg!("successfully acked", item.packet.get_seq());
+                self.events.push_back(TCPEventKind::Acked);
+                // 再送したセグメントは、どの送信に対する ACK か区別できないので計測しない (Karn のアルゴリズム)
+                rtt = (item.transmission_count == 1).then(|| now - item.latest_transmission_time);
+            } else {
+                // ack されていない。戻す。
+                self.retransmission_queue.push_front(item);
+                break;
+            }
+        }
+        // タイムスタンプで計測できる RTT はミリ秒単位なので、輻輳制御には計測できればセグメントの送信時刻からの RTT を渡す
+        let echoed_rtt = self.echoed_rtt(packet, now);
+        if let Some(rtt) = echoed_rtt.or(rtt) {
+            self.stats.rtt.sample(rtt);
+        }
+        rtt.or(echoed_rtt)
+    }
+
+    /// ESTABLISHED 状態のソケットに到着したパケットの処理
+    fn established_handler(&mut self, packet: &TCPPacket, now: Duration) {
+        dbg!("established handler");
+        if !self.process_ack(packet, now) {
+            return;
+        }
+
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            // ACKが経っていないパケットは破棄
+            return;
+        }
+
+        if !packet.payload().is_empty() {
+            self.process_payload(packet, now);
+        }
+
+        // パッシブクローズの処理
+        // ESTABLISHED状態の時に FIN|ACK を相手から受け取ることになるので、ここに処理を書きます。
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            if packet.get_seq() + packet.payload().len() as u32 != self.recv_param.next {
+                // 手前のセグメントが欠けているので、FIN は受け取らずに再送を待つ
+                return;
+            }
+            self.recv_param.next += 1;
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::ACK,
+                &[],
+                now,
+            );
+            self.status = TcpStatus::CloseWait;
+            self.events.push_back(TCPEventKind::DataArrived);
+        }
+    }
+
+    /// SYNRCVD 状態のソケットに到着したパケットの処理
+    fn synrcvd_handler(&mut self, packet: &TCPPacket, now: Duration) {
+        dbg!("synrcvd handler");
+        if packet.get_flag() & tcpflags::ACK == 0 {
+            return;
+        }
+        if !(self.send_param.unacked_seq < packet.get_ack()
+            && packet.get_ack() <= self.send_param.next)
+        {
+            // 送信していない SYN|ACK に対する ACK には RST を返す
+            self.send_reset(packet);
+            return;
+        }
+        self.send_param.unacked_seq = packet.get_ack();
+        self.set_send_window(packet, self.received_window(packet));
+        self.peer_window = self.received_window(packet);
+        self.delete_acked_segment_from_retransmission_queue(packet, now);
+        self.status = TcpStatus::Established;
+        dbg!("status: synrcvd -> ", &self.status);
+        self.events.push_back(TCPEventKind::ConnectionCompleted);
+    }
+
+    /// SYNSENT 状態のソケットに到着したパケットの処理
+    /// NOTE: SYN を送信した後なので、相手からSYN|ACKセグメントを受け取ればコネクションが確立され、アクティブオープンが成功.
+    fn synsent_handler(&mut self, packet: &TCPPacket, now: Duration) {
+        dbg!("synsent handler");
+
+        // NOTE: セグメントが運んでくる確認応答番号は、送信した SYN 以降 `initial_seq < ack <= send_param.next` の範囲に含まれる必要があります。
+        // NOTE: 範囲外であれば、古いコネクションの重複セグメントなどとみなして RST を返す。
+        if packet.get_flag() & tcpflags::ACK > 0
+            && !(self.send_param.initial_seq < packet.get_ack()
+                && packet.get_ack() <= self.send_param.next)
+        {
+            self.send_reset(packet);
+            return;
+        }
+
+        if packet.get_flag() & tcpflags::SYN == 0 {
+            // SYN を含まないセグメントは破棄
+            return;
+        }
+        self.recv_param.next = packet.get_seq() + 1;
+        self.recv_param.initial_seq = packet.get_seq();
+        // SYN のウィンドウはスケールしない
+        self.set_send_window(packet, packet.get_window_size() as u32);
+        self.peer_window = packet.get_window_size() as u32;
+        // SYN で SACK-permitted を送っているので、相手も送ってきていれば SACK を使える
+        self.sack_permitted = packet
+            .options()
+            .any(|option| option == TcpOption::SackPermitted);
+        // SYN でタイムスタンプを送っているので、相手も送ってきていればタイムスタンプを使える
+        if let Some(value) = timestamp_value(packet) {
+            self.timestamps = true;
+            self.ts_recent = value;
+            self.ts_recent_time = now;
+        }
+        // 輻輳ウィンドウの初期値はセグメントのサイズで決まるので、相手の MSS が分かったところで初期化し直す
+        self.send_param.mss = peer_mss(packet);
+        self.set_congestion_control(self.congestion_algorithm);
+        match peer_window_shift(packet) {
+            Some(shift) => self.send_param.window_shift = shift,
+            None => self.recv_param.window_shift = 0,
+        }
+
+        // NOTE: ACK ビットは基本的にONになっている必要がある。例外はソケットがLISTEN状態の時と、同時オープンの時。
+        if packet.get_flag() & tcpflags::ACK > 0 {
+            // 送信した SYN が ack されたので、コネクションが確立した
+            self.send_param.unacked_seq = packet.get_ack();
+            self.delete_acked_segment_from_retransmission_queue(packet, now);
+            self.status = TcpStatus::Established;
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::ACK,
+                &[],
+                now,
+            );
+            dbg!("status: synsent ->", &self.status);
+            self.events.push_back(TCPEventKind::ConnectionCompleted);
+        } else {
+            // 同時オープン: 相手も SYN を送信していて、お互いの SYN が行き違った。
+            // SYN|ACK を送信して、相手からの ACK (相手の SYN|ACK) を SYNRCVD 状態で待つ。
+            self.status = TcpStatus::SynRcvd;
+            self.send_tcp_packet(
+                self.send_param.initial_seq,
+                self.recv_param.next,
+                tcpflags::SYN | tcpflags::ACK,
+                &[],
+                now,
+            );
+            dbg!("status: synsent ->", &self.status);
+        }
+    }
+
+    /// パケットのペイロードを受信バッファにコピーする
+    fn process_payload(&mut self, packet: &TCPPacket, now: Duration) {
+        // 受信ウィンドウ [next, next + window) に収まる部分だけを受け取る
+        let mut seq = packet.get_seq();
+        let mut payload = packet.payload();
+        if seq < self.recv_param.next {
+            // 再送などで、既に受信済みの部分を含んでいる。その部分は読み飛ばす。
+            let received = cmp::min((self.recv_param.next - seq) as usize, payload.len());
+            seq += received as u32;
+            payload = &payload[received..];
+        }
+        let window_end = self.recv_param.next + self.recv_param.window;
+        let acceptable = if seq < window_end {
+            cmp::min(payload.len(), (window_end - seq) as usize)
+        } else {
+            0
+        };
+        payload = &payload[..acceptable];
+
+        if payload.is_empty() {
+            // 受信済みのデータか、受信バッファが溢れている
+            dbg!("no new data");
+        } else if seq == self.recv_param.next {
+            self.push_recv_buffer(payload);
+            // 穴が埋まったので、保管していた続きのデータも受信バッファに移す
+            while let Some(data) = self.reassembly_queue.pop(self.recv_param.next) {
+                self.push_recv_buffer(&data);
+            }
+            self.events.push_back(TCPEventKind::DataArrived);
+        } else {
+            // 手前のセグメントが欠けているので、穴が埋まるまで保管しておく
+            dbg!("out of order segment");
+            self.reassembly_queue.insert(seq, payload);
+            self.last_out_of_order = Some(seq);
+        }
+
+        // 受け取れなかった場合も、次に受信したい seq を伝えるために ACK を返す
+        self.send_tcp_packet(
+            self.send_param.next,
+            self.recv_param.next,
+            tcpflags::ACK,
+            &[],
+            now,
+        );
+    }
+
+    /// 順序通りに揃ったデータを受信バッファの末尾に追加して、recv_param.next を進める。
+    /// data は受信ウィンドウに収まっている必要がある。
+    fn push_recv_buffer(&mut self, data: &[u8]) {
+        let offset = self.recv_buffer.len() - self.recv_param.window as usize;
+        self.recv_buffer[offset..offset + data.len()].copy_from_slice(data);
+        self.recv_param.next += data.len() as u32;
+        self.recv_param.window -= data.len() as u32;
+    }
+
+    /// FINWAIT1 or FINWAIT2 状態のソケットに到着したパケットの処理
+    /// これは、アクティブクローズ状態の時に受信したセグメントのハンドラになる。
+    fn finwait_handler(&mut self, packet: &TCPPacket, now: Duration) {
+        dbg!("finwait handler");
+        if !self.process_ack(packet, now) {
+            return;
+        }
+
+        if !packet.payload().is_empty() {
+            self.process_payload(packet, now);
+        }
+
+        if self.status == TcpStatus::FinWait1 && self.send_param.next == self.send_param.unacked_seq
+        {
+            // 送信したFINがackされていればFinWait2へ遷移
+            self.status = TcpStatus::FinWait2;
+            dbg!("status: finwait1 -> ", &self.status);
+        }
+
+        if packet.get_flag() & tcpflags::FIN > 0 {
+            if packet.get_seq() + packet.payload().len() as u32 != self.recv_param.next {
+                // 手前のセグメントが欠けているので、FIN は受け取らずに再送を待つ
+                return;
+            }
+            self.recv_param.next += 1;
+            self.send_tcp_packet(
+                self.send_param.next,
+                self.recv_param.next,
+                tcpflags::ACK,
+                &[],
+                now,
+            );
+            if self.status == TcpStatus::FinWait1 {
+                // 送信した FIN が ack される前に相手の FIN が届いた。お互いに同時にクローズしている。
+                self.status = TcpStatus::Closing;
+                dbg!("status: finwait1 ->", &self.status);
+            } else {
+                self.enter_time_wait(now);
+            }
+        }
+    }
+
+    /// CLOSING 状態のソケットに到着したパケットの処理
+    /// 同時クローズで、お互いの FIN に ACK を返し合うのを待っている。
+    fn closing_handler(&mut self, packet: &TCPPacket, now: Duration) {
+        dbg!("closing handler");
+        if !self.process_ack(packet, now) {
+            return;
+        }
+        if self.send_param.unacked_seq == self.send_param.next {
+            // 送信した FIN が ack された
+            self.enter_time_wait(now);
+        }
+    }
+
+    /// CLOSEWAIT or LASTACK 状態のソケットに到着したパケットの処理
+    /// これは、パッシブクローズ状態の時に受信したセグメントのハンドラになる。
+    fn close_handler(&mut self, packet: &TCPPacket, now: Duration) {
+        dbg!("closewait | lastack handler");
+        if !self.process_ack(packet, now) {
+            return;
+        }
+        if self.status == TcpStatus::LastAck && self.send_param.unacked_seq == self.send_param.next
+        {
+            // 送信した FIN が ack されたので、コネクションを閉じる
+            self.status = TcpStatus::Closed;
+            dbg!("status: lastack ->", &self.status);
+            self.events.push_back(TCPEventKind::ConnectionClosed);
+        }
+    }
+}
+
+/// packet に対する RST セグメントを生成する (RFC 9293 3.10.7.1)。
+/// packet の ACK が立っていれば SEQ=SEG.ACK の RST を、立っていなければ ACK=SEG.SEQ+SEG.LEN の RST|ACK を返す。
+/// RST に RST を返すと送りあいになってしまうので、packet が RST であれば何も返さない。
+pub fn reset_segment(
+    local_addr: Ipv4Addr,
+    remote_addr: Ipv4Addr,
+    packet: &TCPPacket,
+) -> Option<TCPPacket> {
+    if packet.get_flag() & tcpflags::RST > 0 {
+        return None;
+    }
+    let mut rst = TCPPacket::new(0);
+    rst.set_src(packet.get_dest());
+    rst.set_dest(packet.get_src());
+    if packet.get_flag() & tcpflags::ACK > 0 {
+        rst.set_seq(packet.get_ack());
+        rst.set_flag(tcpflags::RST);
+    } else {
+        rst.set_ack(packet.get_seq() + packet.get_segment_len());
+        rst.set_flag(tcpflags::RST | tcpflags::ACK);
+    }
+    rst.set_data_offset(5);
+    rst.set_checksum(util::ipv4_checksum(
+        rst.packet(),
+        8,
+        &[],
+        &local_addr,
+        &remote_addr,
+        IpNextHeaderProtocols::Tcp,
+    ));
+    Some(rst)
+}
+
+/// size バイトの受信バッファ全体を、16 ビットのウィンドウフィールドで広告するのに必要なウィンドウスケール
+fn window_shift_for(size: usize) -> u8 {
+    let mut shift = 0;
+    while size >> shift > u16::MAX as usize && shift < MAX_WINDOW_SHIFT {
+        shift += 1;
+    }
+    shift
+}
+
+/// SYN で相手が伝えてきたウィンドウスケール。オプションがなければ、ウィンドウスケールは使わない。
+/// 14 より大きい値は 14 として扱う (RFC 7323)。
+fn peer_window_shift(syn: &TCPPacket) -> Option<u8> {
+    syn.options().find_map(|option| match option {
+        TcpOption::WindowScale(shift) => Some(cmp::min(shift, MAX_WINDOW_SHIFT)),
+        _ => None,
+    })
+}
+
+/// now のタイムスタンプ。1ミリ秒ごとに1進めて、32 ビットで一周する。
+fn timestamp(now: Duration) -> u32 {
+    now.as_millis() as u32
+}
+
+/// 一周することを考慮して、タイムスタンプ a が b より前かどうか
+fn timestamp_before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// セグメントに付いているタイムスタンプ (TSval)
+fn timestamp_value(packet: &TCPPacket) -> Option<u32> {
+    packet.options().find_map(|option| match option {
+        TcpOption::Timestamps { value, .. } => Some(value),
+        _ => None,
+    })
+}
+
+/// SYN で相手が伝えてきた MSS。オプションがなければ既定値とする。
+/// オプションを付けるとペイロードを載せられなくなるほど小さい値は、MIN_MSS として扱う。
+fn peer_mss(syn: &TCPPacket) -> usize {
+    syn.options()
+        .find_map(|option| match option {
+            TcpOption::Mss(mss) => Some(cmp::max(mss as usize, MIN_MSS)),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_SEND_MSS)
+}
+
+/// LISTEN 状態のコネクションにセグメントが到着した結果
+pub enum AcceptOutcome {
+    // パッシブオープンした新しいコネクション
+    Accepted(Box<Connection>),
+    // 送信元に返す RST セグメント
+    Reset(TCPPacket),
+    // 何もせずに破棄した
+    Discarded,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TCPEventKind {
+    ConnectionCompleted,
+    Acked,
+    DataArrived,
+    ConnectionClosed,
+    // 異常終了した。待機しているすべての呼び出しを起こし、`Connection::error` を返させる。
+    ConnectionAborted,
+}
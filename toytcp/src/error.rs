@@ -0,0 +1,25 @@
+use std::fmt::{self, Display};
+
+/// コネクションが異常終了した理由。
+/// `TCP` の各メソッドは anyhow::Error に包んで返すので、呼び出し側は `downcast_ref::<TCPError>()` で区別できる。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TCPError {
+    // 接続先に RST で拒否された
+    ConnectionRefused,
+    // 確立済みのコネクションが RST で切断された
+    ConnectionReset,
+    // 再送を続けてもユーザータイムアウトまでに ack されなかった
+    TimedOut,
+}
+
+impl Display for TCPError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TCPError::ConnectionRefused => write!(f, "connection refused"),
+            TCPError::ConnectionReset => write!(f, "connection reset by peer"),
+            TCPError::TimedOut => write!(f, "connection timed out"),
+        }
+    }
+}
+
+impl std::error::Error for TCPError {}
@@ -1,4 +1,15 @@
+pub mod clock;
+pub mod congestion;
+pub mod connection;
+pub mod error;
+pub mod link;
+pub mod loopback;
 pub mod packet;
+pub mod reassembly;
+pub mod rtt;
+pub mod seqnum;
+pub mod simulator;
 pub mod socket;
 pub mod tcp;
 pub mod tcpflags;
+pub mod tcpoption;
@@ -0,0 +1,133 @@
+use anyhow::{Context, Result};
+use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
+use pnet::transport::{
+    self, TransportChannelType, TransportProtocol, TransportReceiver, TransportSender,
+};
+use std::net::{IpAddr, Ipv4Addr};
+use std::process::Command;
+use std::str;
+use std::sync::Mutex;
+
+// インターフェースの MTU が分からない場合に使う、Ethernet の MTU
+pub const DEFAULT_MTU: usize = 1500;
+
+/// TCP セグメントをやり取りするためのバックエンド。
+/// TCP はこのトレイトを通してのみセグメントを送受信するので、Raw Socket 以外の実装（テスト用のリンクや TUN デバイスなど）に差し替えられる。
+pub trait Link: Send + Sync {
+    /// TCP セグメント（ヘッダ + ペイロード）を宛先アドレスに送信する。
+    fn send_to(&self, segment: &[u8], remote_addr: Ipv4Addr) -> Result<usize>;
+
+    /// TCP セグメントを1つ受信するまでブロックし、(送信元アドレス, 宛先アドレス, セグメント) を返す。
+    fn recv(&self) -> Result<(Ipv4Addr, Ipv4Addr, Vec<u8>)>;
+
+    /// 宛先アドレスに対する送信元インターフェースのIPアドレスを返す。
+    fn source_addr_to(&self, remote_addr: Ipv4Addr) -> Result<Ipv4Addr>;
+
+    /// local_addr を持つインターフェースの MTU を返す。SYN で伝える MSS はここから決める。
+    fn mtu(&self, _local_addr: Ipv4Addr) -> Result<usize> {
+        Ok(DEFAULT_MTU)
+    }
+}
+
+/// pnet の Raw Socket を使ったリンク。root 権限が必要。
+pub struct PnetLink {
+    sender: Mutex<TransportSender>,
+    receiver: Mutex<TransportReceiver>,
+}
+
+impl PnetLink {
+    pub fn new() -> Result<Self> {
+        // 内部的に Raw Socket を用いており、TCPのフォーマットに成形されたバイト列を書き込んで送信ができる。
+        let (sender, _) = transport::transport_channel(
+            65535,
+            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
+        )?;
+        let (_, receiver) = transport::transport_channel(
+            65535,
+            // NOTE: IPアドレスが必要なので、IPパケットレベルで取得.
+            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
+        )?;
+        Ok(Self {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+        })
+    }
+}
+
+impl Link for PnetLink {
+    fn send_to(&self, segment: &[u8], remote_addr: Ipv4Addr) -> Result<usize> {
+        let packet = TcpPacket::new(segment).context("segment is too short")?;
+        self.sender
+            .lock()
+            .unwrap()
+            .send_to(packet, IpAddr::V4(remote_addr))
+            .context("failed to send")
+    }
+
+    fn recv(&self) -> Result<(Ipv4Addr, Ipv4Addr, Vec<u8>)> {
+        let mut receiver = self.receiver.lock().unwrap();
+        // NOTE: このイテレータに対して`next()`を呼び出すと、パケットを受信するまでスレッドをブロックして待機します。
+        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
+        loop {
+            let (packet, remote_addr) = match packet_iter.next() {
+                Ok((p, r)) => (p, r),
+                Err(_) => continue,
+            };
+            let remote_addr = match remote_addr {
+                IpAddr::V4(addr) => addr,
+                _ => continue,
+            };
+            return Ok((
+                remote_addr,
+                packet.get_destination(),
+                packet.payload().to_vec(),
+            ));
+        }
+    }
+
+    fn source_addr_to(&self, remote_addr: Ipv4Addr) -> Result<Ipv4Addr> {
+        get_source_addr_to(remote_addr)
+    }
+
+    fn mtu(&self, local_addr: Ipv4Addr) -> Result<usize> {
+        get_mtu(local_addr)
+    }
+}
+
+/// 宛先IPアドレスに対する送信もとインターフェースのIPアドレスを取得する。
+/// iproute2-ss180129 で動作を確認。バージョンによって挙動が変わるかも。
+fn get_source_addr_to(addr: Ipv4Addr) -> Result<Ipv4Addr> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("ip route get {} | grep src", addr))
+        .output()?;
+    let mut output = str::from_utf8(&output.stdout)?
+        .trim()
+        .split_ascii_whitespace();
+    for s in output.by_ref() {
+        if s == "src" {
+            break;
+        }
+    }
+    let ip = output.next().context("failed to get src ip")?;
+    dbg!("source addr", ip);
+    ip.parse().context("failed to parse source ip")
+}
+
+/// IPアドレスを持つインターフェースの MTU を取得する。
+/// `ip -o addr` の出力からインターフェース名を探し、sysfs から MTU を読む。
+fn get_mtu(addr: Ipv4Addr) -> Result<usize> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("ip -o -4 addr show to {}", addr))
+        .output()?;
+    // "2: eth0    inet 10.0.0.1/24 ..." の2つ目がインターフェース名
+    let interface = str::from_utf8(&output.stdout)?
+        .split_ascii_whitespace()
+        .nth(1)
+        .context("failed to get interface")?
+        .to_string();
+    let mtu = std::fs::read_to_string(format!("/sys/class/net/{}/mtu", interface))?;
+    dbg!("mtu", &interface, &mtu);
+    mtu.trim().parse().context("failed to parse mtu")
+}
@@ -1,10 +1,12 @@
+use crate::seqnum::SeqNum;
 use crate::tcpflags;
+use crate::tcpoption::{self, Options, TcpOption};
 use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
 use pnet::util;
 
 use std::fmt::{self, Debug};
 use std::net::Ipv4Addr;
-const TCP_HEADER_SIZE: usize = 20;
+pub const TCP_HEADER_SIZE: usize = 20;
 
 #[derive(Clone)]
 pub struct TCPPacket {
@@ -13,9 +15,12 @@ pub struct TCPPacket {
 
 impl TCPPacket {
     pub fn new(payload_len: usize) -> Self {
-        Self {
+        let mut packet = Self {
             buffer: vec![0; TCP_HEADER_SIZE + payload_len],
-        }
+        };
+        // オプションを付けるまでは、ヘッダは 32-bit words * 5 分になる
+        packet.set_data_offset(5);
+        packet
     }
 
     pub fn get_src(&self) -> u16 {
@@ -34,34 +39,63 @@ impl TCPPacket {
         self.buffer[2..4].copy_from_slice(&port.to_be_bytes());
     }
 
-    pub fn get_seq(&self) -> u32 {
-        u32::from_be_bytes([
+    pub fn get_seq(&self) -> SeqNum {
+        SeqNum::from(u32::from_be_bytes([
             self.buffer[4],
             self.buffer[5],
             self.buffer[6],
             self.buffer[7],
-        ])
+        ]))
     }
 
-    pub fn set_seq(&mut self, num: u32) {
-        self.buffer[4..8].copy_from_slice(&num.to_be_bytes());
+    pub fn set_seq(&mut self, num: SeqNum) {
+        self.buffer[4..8].copy_from_slice(&num.get().to_be_bytes());
     }
 
-    pub fn get_ack(&self) -> u32 {
-        u32::from_be_bytes([
+    pub fn get_ack(&self) -> SeqNum {
+        SeqNum::from(u32::from_be_bytes([
             self.buffer[8],
             self.buffer[9],
             self.buffer[10],
             self.buffer[11],
-        ])
+        ]))
+    }
+
+    pub fn set_ack(&mut self, num: SeqNum) {
+        self.buffer[8..12].copy_from_slice(&num.get().to_be_bytes());
     }
 
-    pub fn set_ack(&mut self, num: u32) {
-        self.buffer[8..12].copy_from_slice(&num.to_be_bytes());
+    pub fn get_data_offset(&self) -> u8 {
+        self.buffer[12] >> 4
     }
 
     pub fn set_data_offset(&mut self, offset: u8) {
-        self.buffer[12] |= offset << 4;
+        self.buffer[12] = (self.buffer[12] & 0x0f) | (offset << 4);
+    }
+
+    /// data offset がヘッダの最小の長さ以上で、セグメントの範囲に収まっているかどうか。
+    pub fn has_valid_data_offset(&self) -> bool {
+        let header_len = self.get_data_offset() as usize * 4;
+        TCP_HEADER_SIZE <= header_len && header_len <= self.buffer.len()
+    }
+
+    /// オプションを含めたヘッダのバイト数。data offset が壊れている場合も、バッファの範囲に収める。
+    fn header_len(&self) -> usize {
+        (self.get_data_offset() as usize * 4).clamp(TCP_HEADER_SIZE, self.buffer.len())
+    }
+
+    pub fn options(&self) -> Options<'_> {
+        tcpoption::parse(&self.buffer[TCP_HEADER_SIZE..self.header_len()])
+    }
+
+    /// オプションを設定し、その分だけ data offset を大きくする。ペイロードは後ろにずれるので、set_payload より前に呼ぶこと。
+    pub fn set_options(&mut self, options: &[TcpOption]) {
+        let bytes = tcpoption::build(options);
+        assert!(bytes.len() <= tcpoption::MAX_OPTIONS_SIZE);
+        let header_len = self.header_len();
+        self.buffer
+            .splice(TCP_HEADER_SIZE..header_len, bytes.iter().copied());
+        self.set_data_offset(((TCP_HEADER_SIZE + bytes.len()) / 4) as u8);
     }
 
     pub fn get_flag(&self) -> u8 {
@@ -89,14 +123,26 @@ impl TCPPacket {
     }
 
     pub fn set_payload(&mut self, payload: &[u8]) {
-        self.buffer[TCP_HEADER_SIZE..TCP_HEADER_SIZE + payload.len() as usize]
-            .copy_from_slice(payload);
+        let header_len = self.header_len();
+        self.buffer[header_len..header_len + payload.len()].copy_from_slice(payload);
+    }
+
+    /// シーケンス番号空間で消費する長さ。SYN と FIN はそれぞれ1つ分として数える。
+    pub fn get_segment_len(&self) -> u32 {
+        let mut len = self.payload().len() as u32;
+        if self.get_flag() & tcpflags::SYN > 0 {
+            len += 1;
+        }
+        if self.get_flag() & tcpflags::FIN > 0 {
+            len += 1;
+        }
+        len
     }
 
     pub fn is_correct_checksum(&self, local_addr: Ipv4Addr, remote_addr: Ipv4Addr) -> bool {
         self.get_checksum()
             == util::ipv4_checksum(
-                &self.packet(),
+                self.packet(),
                 8,
                 &[],
                 &local_addr,
@@ -112,7 +158,7 @@ impl Packet for TCPPacket {
     }
 
     fn payload(&self) -> &[u8] {
-        &self.buffer[TCP_HEADER_SIZE..]
+        &self.buffer[self.header_len()..]
     }
 }
 
@@ -124,10 +170,12 @@ impl Debug for TCPPacket {
         src: {}
         dst: {}
         flag: {}
+        options: {:?}
         payload_len: {}",
             self.get_src(),
             self.get_dest(),
             tcpflags::flag_to_string(self.get_flag()),
+            self.options().collect::<Vec<_>>(),
             self.payload().len()
         )
     }
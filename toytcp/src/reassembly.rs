@@ -0,0 +1,112 @@
+use crate::seqnum::SeqNum;
+use std::cmp;
+
+/// 順序が入れ替わって届いたデータを、手前の穴が埋まるまで保管しておく。
+/// 保管しているデータは seq の順に並んだ重複のない区間の集合で、隣接する区間は1つにまとめる。
+#[derive(Debug, Default)]
+pub struct ReassemblyQueue {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    seq: SeqNum,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn end(&self) -> SeqNum {
+        self.seq + self.data.len() as u32
+    }
+}
+
+impl ReassemblyQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// seq から始まる data を保管する。既に保管している部分と重なる場合は、重ならない部分だけを追加する。
+    pub fn insert(&mut self, mut seq: SeqNum, mut data: &[u8]) {
+        let mut i = 0;
+        while !data.is_empty() {
+            match self.segments.get(i) {
+                // data より前にある区間
+                Some(segment) if segment.end() <= seq => i += 1,
+                // data の先頭と重なっている区間。重なっている部分を切り捨てる
+                Some(segment) if segment.seq <= seq => {
+                    let overlap = cmp::min((segment.end() - seq) as usize, data.len());
+                    seq += overlap as u32;
+                    data = &data[overlap..];
+                    i += 1;
+                }
+                // data の後ろにある区間。その手前までの穴を埋める
+                Some(segment) => {
+                    let len = cmp::min((segment.seq - seq) as usize, data.len());
+                    self.segments.insert(
+                        i,
+                        Segment {
+                            seq,
+                            data: data[..len].to_vec(),
+                        },
+                    );
+                    seq += len as u32;
+                    data = &data[len..];
+                    i += 1;
+                }
+                None => {
+                    self.segments.push(Segment {
+                        seq,
+                        data: data.to_vec(),
+                    });
+                    break;
+                }
+            }
+        }
+        self.merge();
+    }
+
+    /// next から途切れずに続くデータを取り出す。next より前の部分は受信済みなので捨てる。
+    pub fn pop(&mut self, next: SeqNum) -> Option<Vec<u8>> {
+        while let Some(segment) = self.segments.first() {
+            if next < segment.seq {
+                // まだ穴が埋まっていない
+                return None;
+            }
+            let mut segment = self.segments.remove(0);
+            if next < segment.end() {
+                segment.data.drain(..(next - segment.seq) as usize);
+                return Some(segment.data);
+            }
+        }
+        None
+    }
+
+    /// 保管している区間を (左端, 右端) の組で seq の順に返す。右端の seq は区間に含まない。
+    /// SACK オプションで、受信済みのブロックを相手に伝えるのに使う。
+    pub fn ranges(&self) -> impl Iterator<Item = (SeqNum, SeqNum)> + '_ {
+        self.segments
+            .iter()
+            .map(|segment| (segment.seq, segment.end()))
+    }
+
+    /// 保管しているデータのバイト数
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|segment| segment.data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    fn merge(&mut self) {
+        let mut i = 1;
+        while i < self.segments.len() {
+            if self.segments[i - 1].end() == self.segments[i].seq {
+                let segment = self.segments.remove(i);
+                self.segments[i - 1].data.extend_from_slice(&segment.data);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
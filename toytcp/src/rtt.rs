@@ -0,0 +1,64 @@
+use std::cmp;
+use std::time::Duration;
+
+// RFC 6298 の推奨値
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+// 時刻は Duration で扱っているので、計測の粒度は十分に細かいものとする
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// 往復遅延時間 (RTT) を計測して、再送タイムアウト (RTO) を決める (RFC 6298)。
+#[derive(Clone, Debug)]
+pub struct RttEstimator {
+    pub srtt: Option<Duration>, // 平滑化した RTT。まだ計測していなければ None
+    pub rttvar: Duration,       // RTT のばらつき
+    pub rto: Duration,          // 再送タイムアウト
+}
+
+impl Default for RttEstimator {
+    fn default() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            rto: INITIAL_RTO,
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 計測した RTT を反映して、RTO を計算し直す。
+    /// 再送したセグメントの ACK からは、どの送信に対する ACK か区別できないので、計測しないこと (Karn のアルゴリズム)。
+    pub fn sample(&mut self, rtt: Duration) {
+        let srtt = match self.srtt {
+            None => {
+                self.rttvar = rtt / 2;
+                rtt
+            }
+            Some(srtt) => {
+                // RTTVAR <- 3/4 * RTTVAR + 1/4 * |SRTT - R'|
+                // SRTT <- 7/8 * SRTT + 1/8 * R'
+                self.rttvar = self.rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4;
+                srtt * 7 / 8 + rtt / 8
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rto = (srtt + cmp::max(CLOCK_GRANULARITY, self.rttvar * 4)).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    /// 再送タイムアウトが起きたので、RTO を2倍にする (RFC 6298 5.5)。
+    /// 次に RTT を計測するまでは、後から送信したセグメントにも2倍にした RTO を使う。
+    pub fn on_timeout(&mut self) {
+        self.rto = cmp::min(self.rto.saturating_mul(2), MAX_RTO);
+    }
+
+    /// transmission_count 回送信したセグメントを、次に再送するまでの時間。再送するたびに2倍にする。
+    pub fn backoff(&self, transmission_count: u8) -> Duration {
+        let factor = 2u32.saturating_pow(transmission_count.saturating_sub(1) as u32);
+        cmp::min(self.rto.saturating_mul(factor), MAX_RTO)
+    }
+}
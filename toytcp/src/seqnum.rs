@@ -0,0 +1,76 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+use std::ops::{Add, AddAssign, Sub};
+
+/// TCP のシーケンス番号。
+/// シーケンス番号は 2^32 で一周するので、大小比較と加減算は RFC 1982 のように剰余で行う。
+/// a < b は「b が a から 2^31 未満だけ先にある」ことを意味するので、全順序ではない (Ord は実装しない)。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct SeqNum(u32);
+
+impl SeqNum {
+    pub const fn new(num: u32) -> Self {
+        Self(num)
+    }
+
+    /// ヘッダに書き込む値
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for SeqNum {
+    fn from(num: u32) -> Self {
+        Self(num)
+    }
+}
+
+impl PartialOrd for SeqNum {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        // 差を符号付きで解釈して、どちらが先にあるかを判定する
+        match self.0.wrapping_sub(other.0) {
+            0 => Some(Ordering::Equal),
+            // ちょうど半周離れている場合は、どちらが先とも言えない
+            0x8000_0000 => None,
+            diff if diff < 0x8000_0000 => Some(Ordering::Greater),
+            _ => Some(Ordering::Less),
+        }
+    }
+}
+
+impl Add<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn add(self, rhs: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_add(rhs))
+    }
+}
+
+impl AddAssign<u32> for SeqNum {
+    fn add_assign(&mut self, rhs: u32) {
+        self.0 = self.0.wrapping_add(rhs);
+    }
+}
+
+impl Sub<u32> for SeqNum {
+    type Output = SeqNum;
+
+    fn sub(self, rhs: u32) -> SeqNum {
+        SeqNum(self.0.wrapping_sub(rhs))
+    }
+}
+
+/// rhs から self までの距離。self が rhs より前にある場合は 2^32 から引いた値になるので、先に比較しておくこと。
+impl Sub<SeqNum> for SeqNum {
+    type Output = u32;
+
+    fn sub(self, rhs: SeqNum) -> u32 {
+        self.0.wrapping_sub(rhs.0)
+    }
+}
+
+impl Display for SeqNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,373 @@
+use crate::clock::{Clock, SystemClock};
+use crate::link::Link;
+use crate::packet::TCPPacket;
+use anyhow::Result;
+use pnet::packet::tcp::TcpPacket;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// 遅延用スレッドが時計を確認する間隔
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// セグメントに加える障害
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Impairment {
+    /// 破棄する
+    Drop,
+    /// 2回送信する
+    Duplicate,
+    /// 指定時間だけ遅らせて送信する
+    Delay(Duration),
+    /// 次のセグメントが送信されるまで保留し、そのセグメントに追い越させる。
+    /// max_delay が経過しても次のセグメントが送信されなければ、そのまま送る
+    Reorder,
+    /// 先頭から指定バイトだけを残して切り詰める
+    Truncate(usize),
+    /// offset バイト目（セグメント長で剰余をとる）の bit ビット目を反転する
+    Corrupt { offset: usize, bit: u8 },
+}
+
+/// 確率的に加える障害の設定。各 rate はセグメントごとの発生確率 (0.0 ~ 1.0)。
+#[derive(Debug, Clone)]
+pub struct SimulatorConfig {
+    pub seed: u64,
+    pub loss_rate: f64,
+    pub duplicate_rate: f64,
+    pub reorder_rate: f64,
+    pub delay_rate: f64,
+    pub max_delay: Duration,
+    pub truncate_rate: f64,
+    pub corrupt_rate: f64,
+}
+
+impl Default for SimulatorConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            loss_rate: 0.0,
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            delay_rate: 0.0,
+            max_delay: Duration::from_millis(50),
+            truncate_rate: 0.0,
+            corrupt_rate: 0.0,
+        }
+    }
+}
+
+/// 条件にマッチしたセグメントに決まった障害を加えるルール。確率的な障害より優先される。
+pub struct Rule {
+    filter: Box<dyn Fn(&TCPPacket) -> bool + Send>,
+    impairment: Impairment,
+    remaining: usize,
+}
+
+impl Rule {
+    /// filter にマッチした最初のセグメントに impairment を加えるルールを作る。
+    pub fn once(
+        impairment: Impairment,
+        filter: impl Fn(&TCPPacket) -> bool + Send + 'static,
+    ) -> Self {
+        Self {
+            filter: Box::new(filter),
+            impairment,
+            remaining: 1,
+        }
+    }
+
+    /// ルールを適用する回数を変更する。
+    pub fn times(mut self, count: usize) -> Self {
+        self.remaining = count;
+        self
+    }
+}
+
+/// 他のリンクをラップして、送信するセグメントに障害を加えるリンク。
+/// 障害の判定はシード付きの乱数で行うので、同じシードと同じセグメント列からは同じ障害列が再現される。
+/// 遅延は指定した時計で測り、送信時刻を過ぎたセグメントは後から送信されるセグメントより先に送るので、
+/// `ManualClock` を使えば届く順序もシードと時計の進め方だけで決まる。
+pub struct SimulatorLink {
+    inner: Arc<dyn Link>,
+    config: SimulatorConfig,
+    clock: Arc<dyn Clock>,
+    state: Mutex<SimulatorState>,
+    pending: Arc<Pending>,
+}
+
+struct SimulatorState {
+    rng: StdRng,
+    rules: Vec<Rule>,
+    // これまでに送信されたセグメントの数
+    segment_count: usize,
+    // (セグメントの通し番号, 加えた障害) の履歴
+    history: Vec<(usize, Impairment)>,
+}
+
+type Pending = (Mutex<PendingSegments>, Condvar);
+
+/// 遅延させたり、Reorder で保留したりして、まだ内側のリンクへ送っていないセグメント
+#[derive(Default)]
+struct PendingSegments {
+    delayed: BinaryHeap<Reverse<DelayedSegment>>,
+    // Reorder で保留しているセグメント
+    held: Option<HeldSegment>,
+    // これまでに delayed に入れたセグメントの数
+    count: usize,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct DelayedSegment {
+    deliver_at: Duration,
+    // 同時刻のセグメントは delayed に入れた順に届ける
+    order: usize,
+    segment: Vec<u8>,
+    remote_addr: Ipv4Addr,
+}
+
+struct HeldSegment {
+    segment: Vec<u8>,
+    remote_addr: Ipv4Addr,
+    copies: usize,
+    // 保留を解いてから、さらに遅らせる時間
+    delay: Duration,
+    // 次のセグメントが送信されなくても、保留を解く時刻
+    release_at: Duration,
+}
+
+impl SimulatorLink {
+    pub fn new(inner: Arc<dyn Link>, config: SimulatorConfig) -> Arc<Self> {
+        Self::with_clock(inner, config, Arc::new(SystemClock::new()))
+    }
+
+    /// 遅延を測る時計を指定して SimulatorLink を生成する。
+    pub fn with_clock(
+        inner: Arc<dyn Link>,
+        config: SimulatorConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Arc<Self> {
+        let pending: Arc<Pending> = Arc::new((Mutex::default(), Condvar::new()));
+        let link = Arc::new(Self {
+            inner: inner.clone(),
+            state: Mutex::new(SimulatorState {
+                rng: StdRng::seed_from_u64(config.seed),
+                rules: Vec::new(),
+                segment_count: 0,
+                history: Vec::new(),
+            }),
+            config,
+            clock: clock.clone(),
+            pending: pending.clone(),
+        });
+        std::thread::spawn(move || {
+            // 遅延させたセグメントを送り出すスレッド
+            delay_handler(inner, pending, clock);
+        });
+        link
+    }
+
+    pub fn add_rule(&self, rule: Rule) {
+        self.state.lock().unwrap().rules.push(rule);
+    }
+
+    /// これまでに加えた障害の履歴を返す。
+    pub fn history(&self) -> Vec<(usize, Impairment)> {
+        self.state.lock().unwrap().history.clone()
+    }
+}
+
+impl SimulatorState {
+    /// セグメントに加える障害を決める。
+    fn decide(&mut self, segment: &[u8], config: &SimulatorConfig) -> Vec<Impairment> {
+        // NOTE: ルールにマッチするかどうかに関わらずセグメントごとに同じ数だけ乱数を消費して、
+        // NOTE: 同じシードなら常に同じ判定列になるようにする。
+        let loss = self.rng.gen_bool(config.loss_rate);
+        let duplicate = self.rng.gen_bool(config.duplicate_rate);
+        let reorder = self.rng.gen_bool(config.reorder_rate);
+        let delay = self.rng.gen_bool(config.delay_rate);
+        let delay_time = self.rng.gen_range(Duration::ZERO..=config.max_delay);
+        let truncate = self.rng.gen_bool(config.truncate_rate);
+        let truncate_len = self.rng.gen_range(0..segment.len().max(1));
+        let corrupt = self.rng.gen_bool(config.corrupt_rate);
+        let corrupt_offset = self.rng.gen_range(0..segment.len().max(1));
+        let corrupt_bit = self.rng.gen_range(0..8);
+
+        if let Some(packet) = TcpPacket::new(segment).map(TCPPacket::from) {
+            if let Some(rule) = self
+                .rules
+                .iter_mut()
+                .find(|rule| rule.remaining > 0 && (rule.filter)(&packet))
+            {
+                rule.remaining -= 1;
+                return vec![rule.impairment.clone()];
+            }
+        }
+
+        if loss {
+            return vec![Impairment::Drop];
+        }
+        let mut impairments = Vec::new();
+        if truncate {
+            impairments.push(Impairment::Truncate(truncate_len));
+        }
+        if corrupt {
+            impairments.push(Impairment::Corrupt {
+                offset: corrupt_offset,
+                bit: corrupt_bit,
+            });
+        }
+        if duplicate {
+            impairments.push(Impairment::Duplicate);
+        }
+        if delay {
+            impairments.push(Impairment::Delay(delay_time));
+        }
+        if reorder {
+            impairments.push(Impairment::Reorder);
+        }
+        impairments
+    }
+}
+
+impl Link for SimulatorLink {
+    fn send_to(&self, segment: &[u8], remote_addr: Ipv4Addr) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let id = state.segment_count;
+        state.segment_count += 1;
+        let impairments = state.decide(segment, &self.config);
+
+        let mut data = segment.to_vec();
+        let mut copies = 1;
+        let mut delay = Duration::ZERO;
+        let mut reorder = false;
+        let mut dropped = false;
+        for impairment in impairments {
+            dbg!("impairment", id, &impairment);
+            match impairment {
+                Impairment::Drop => dropped = true,
+                Impairment::Duplicate => copies = 2,
+                Impairment::Delay(d) => delay = d,
+                Impairment::Reorder => reorder = true,
+                Impairment::Truncate(len) => data.truncate(len),
+                Impairment::Corrupt { offset, bit } => {
+                    if !data.is_empty() {
+                        let offset = offset % data.len();
+                        data[offset] ^= 1 << (bit % 8);
+                    }
+                }
+            }
+            state.history.push((id, impairment));
+        }
+
+        let now = self.clock.now();
+        let (lock, cvar) = &*self.pending;
+        let mut pending = lock.lock().unwrap();
+        // 保留中のセグメントは、このセグメントの後ろに送る
+        let held = pending.held.take();
+        if reorder {
+            pending.held = Some(HeldSegment {
+                segment: data,
+                remote_addr,
+                copies,
+                delay,
+                release_at: now + self.config.max_delay,
+            });
+        } else if !dropped {
+            for _ in 0..copies {
+                pending.push(data.clone(), remote_addr, now + delay);
+            }
+        }
+        if let Some(held) = held {
+            pending.release(held, now);
+        }
+        pending.deliver_due(&*self.inner, now)?;
+        cvar.notify_all();
+        Ok(segment.len())
+    }
+
+    fn recv(&self) -> Result<(Ipv4Addr, Ipv4Addr, Vec<u8>)> {
+        self.inner.recv()
+    }
+
+    fn source_addr_to(&self, remote_addr: Ipv4Addr) -> Result<Ipv4Addr> {
+        self.inner.source_addr_to(remote_addr)
+    }
+
+    fn mtu(&self, local_addr: Ipv4Addr) -> Result<usize> {
+        self.inner.mtu(local_addr)
+    }
+}
+
+impl PendingSegments {
+    fn push(&mut self, segment: Vec<u8>, remote_addr: Ipv4Addr, deliver_at: Duration) {
+        self.delayed.push(Reverse(DelayedSegment {
+            deliver_at,
+            order: self.count,
+            segment,
+            remote_addr,
+        }));
+        self.count += 1;
+    }
+
+    /// 保留していたセグメントを、指定された遅延の後に送るようにする。
+    fn release(&mut self, held: HeldSegment, now: Duration) {
+        for _ in 0..held.copies {
+            self.push(held.segment.clone(), held.remote_addr, now + held.delay);
+        }
+    }
+
+    /// 送信時刻になったセグメントを、時刻の順に内側のリンクへ送る。
+    fn deliver_due(&mut self, inner: &dyn Link, now: Duration) -> Result<()> {
+        if self
+            .held
+            .as_ref()
+            .is_some_and(|held| held.release_at <= now)
+        {
+            let held = self.held.take().unwrap();
+            self.release(held, now);
+        }
+        while self
+            .delayed
+            .peek()
+            .is_some_and(|Reverse(d)| d.deliver_at <= now)
+        {
+            let Reverse(d) = self.delayed.pop().unwrap();
+            inner.send_to(&d.segment, d.remote_addr)?;
+        }
+        Ok(())
+    }
+
+    /// 次にセグメントを送る必要がある時刻
+    fn next_deadline(&self) -> Option<Duration> {
+        let delayed = self.delayed.peek().map(|Reverse(d)| d.deliver_at);
+        let held = self.held.as_ref().map(|held| held.release_at);
+        delayed.into_iter().chain(held).min()
+    }
+}
+
+/// 遅延用スレッドの関数
+/// 時計が送信時刻を過ぎたセグメントから順に内側のリンクへ送る。
+fn delay_handler(inner: Arc<dyn Link>, pending: Arc<Pending>, clock: Arc<dyn Clock>) {
+    let (lock, cvar) = &*pending;
+    let mut segments = lock.lock().unwrap();
+    loop {
+        let now = clock.now();
+        if let Err(error) = segments.deliver_due(&*inner, now) {
+            dbg!(error);
+        }
+        match segments.next_deadline() {
+            Some(deadline) => {
+                // 待っている間に、より早く送るべきセグメントが追加されることもあるので、区切って待つ
+                drop(segments);
+                clock.sleep(cmp::min(deadline - now, POLL_INTERVAL));
+                segments = lock.lock().unwrap();
+            }
+            None => {
+                segments = cvar.wait(segments).unwrap();
+            }
+        }
+    }
+}
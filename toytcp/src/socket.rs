@@ -1,15 +1,11 @@
+use crate::connection::Connection;
 use crate::packet::TCPPacket;
-use crate::tcpflags;
-use anyhow::{Context, Result};
-use pnet::packet::{ip::IpNextHeaderProtocols, Packet};
-use pnet::transport::{self, TransportChannelType, TransportProtocol, TransportSender};
-use pnet::util;
+use crate::rtt::RttEstimator;
+use crate::seqnum::SeqNum;
 use std::collections::VecDeque;
 use std::fmt::{self, Display};
-use std::net::{IpAddr, Ipv4Addr};
-use std::time::SystemTime;
-
-const SOCKET_BUFFER_SIZE: usize = 4380;
+use std::net::Ipv4Addr;
+use std::time::Duration;
 
 /// (loal_addr, remote_addr, local_port, remote_port) のタプルでコネクションを識別する。
 /// ソケットはそのエンドポイントになる。
@@ -17,43 +13,50 @@ const SOCKET_BUFFER_SIZE: usize = 4380;
 #[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
 pub struct SockID(pub Ipv4Addr, pub Ipv4Addr, pub u16, pub u16);
 
+/// TCP が管理するソケット。コネクションの状態機械に、accept のための情報を加えたもの。
 pub struct Socket {
-    pub local_addr: Ipv4Addr,
-    pub remote_addr: Ipv4Addr,
-    pub local_port: u16,
-    pub remote_port: u16,
-    pub send_param: SendParam,
-    pub recv_param: RecvParam,
-    pub status: TcpStatus,
-
-    // 到着したデータを一度保管する。TCPセグメントは通信の途中で順番が入れ替わったり失われたり色々あるので。
-    pub recv_buffer: Vec<u8>,
-
-    pub retransmission_queue: VecDeque<RetransmissionQueueEntry>,
+    pub connection: Connection,
 
     // 接続済みソケットを保持するキュー。りすにんぐそけっとのみ使用。
     pub connected_connection_euque: VecDeque<SockID>,
 
-    // 生成元のリスニングソケット。接続済みソケットのみ使用。
+    // 生成元のリスニングソケット。accept されるまでの接続済みソケットのみ使用。
     pub listening_socket: Option<SockID>,
-
-    pub sender: TransportSender,
 }
 
 #[derive(Clone, Debug)]
 pub struct SendParam {
-    pub unacked_seq: u32, // 送信後、まだ ack されていない seq の先頭
-    pub next: u32,        // 次の送信
-    pub window: u16,      // 送信ウィンドウサイズ
-    pub initial_seq: u32, // 初期送信 seq
+    pub unacked_seq: SeqNum, // 送信後、まだ ack されていない seq の先頭
+    pub next: SeqNum,        // 次の送信
+    pub window: u32, // 相手が広告してきたウィンドウサイズ。unacked_seq から window バイトまで送信できる
+    pub wl1: SeqNum, // window を更新したセグメントの seq
+    pub wl2: SeqNum, // window を更新したセグメントの ack
+    pub initial_seq: SeqNum, // 初期送信 seq
+    pub mss: usize,  // 相手が SYN で伝えてきた MSS
+    pub window_shift: u8, // 相手が SYN で伝えてきたウィンドウスケール
 }
 
 #[derive(Clone, Debug)]
 pub struct RecvParam {
-    pub next: u32,        // 次に受診する seq
-    pub window: u16,      // 受信ウィンドウサイズ
-    pub initial_seq: u32, // 初期受信 seq
-    pub tail: u32,        // 受信 seq の最後尾
+    pub next: SeqNum,        // 次に受診する seq
+    pub window: u32,         // 受信ウィンドウサイズ
+    pub initial_seq: SeqNum, // 初期受信 seq
+    pub window_shift: u8,    // SYN で相手に伝えたウィンドウスケール
+}
+
+/// ソケットごとの統計情報
+#[derive(Clone, Debug, Default)]
+pub struct SocketStats {
+    pub dropped_out_of_window: u64, // seq が受信ウィンドウ外で破棄したセグメント数
+    pub dropped_bad_ack: u64,       // 未送信の seq に対する ACK で破棄したセグメント数
+    pub dropped_paws: u64,          // タイムスタンプが古い (PAWS) ので破棄したセグメント数
+    pub window_probes: u64,         // ウィンドウが 0 の相手に送ったプローブの数
+    pub retransmissions: u64,       // タイムアウトで再送したセグメント数
+    pub fast_retransmissions: u64,  // 重複 ACK で高速再送したセグメント数
+    pub rtt: RttEstimator,          // RTT の推定値と、それから計算した再送タイムアウト
+    pub cwnd: usize,                // 輻輳ウィンドウのバイト数
+    pub ssthresh: usize,            // スロースタートを終える閾値のバイト数
+    pub pacing_rate: Option<u64>,   // ペーシングの送信レート (バイト/秒)
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -64,9 +67,11 @@ pub enum TcpStatus {
     Established,
     FinWait1,
     FinWait2,
+    Closing,
     TimeWait,
     CloseWait,
     LastAck,
+    Closed,
 }
 
 impl Display for TcpStatus {
@@ -78,124 +83,46 @@ impl Display for TcpStatus {
             TcpStatus::Established => write!(f, "ESTABLISHED"),
             TcpStatus::FinWait1 => write!(f, "FINWAIT1"),
             TcpStatus::FinWait2 => write!(f, "FINWAIT2"),
+            TcpStatus::Closing => write!(f, "CLOSING"),
             TcpStatus::TimeWait => write!(f, "TIMEWAIT"),
             TcpStatus::CloseWait => write!(f, "CLOSEWAIT"),
             TcpStatus::LastAck => write!(f, "LASTACK"),
+            TcpStatus::Closed => write!(f, "CLOSED"),
         }
     }
 }
 
 impl Socket {
-    pub fn new(
-        local_addr: Ipv4Addr,
-        remote_addr: Ipv4Addr,
-        local_port: u16,
-        remote_port: u16,
-        status: TcpStatus,
-    ) -> Result<Self> {
-        // ここでtransport layerにおけるやり取りをするためのchannelを作成してるっぽい。
-        // TCP 接続とは違うだろう。ポートとか指定してないので。
-        // 内部的に Raw Socket を用いており、TCPのフォーマットに成形されたバイト列を書き込んで送信ができるとのこと。
-        let (sender, _) = transport::transport_channel(
-            65535,
-            TransportChannelType::Layer4(TransportProtocol::Ipv4(IpNextHeaderProtocols::Tcp)),
-        )?;
-
-        Ok(Self {
-            local_addr,
-            remote_addr,
-            local_port,
-            remote_port,
-            send_param: SendParam {
-                unacked_seq: 0,
-                initial_seq: 0,
-                next: 0,
-                window: SOCKET_BUFFER_SIZE as u16,
-            },
-            recv_param: RecvParam {
-                initial_seq: 0,
-                next: 0,
-                window: SOCKET_BUFFER_SIZE as u16,
-                tail: 0,
-            },
-            status,
-            recv_buffer: vec![0; SOCKET_BUFFER_SIZE],
-            retransmission_queue: VecDeque::new(),
+    pub fn new(connection: Connection) -> Self {
+        Self {
+            connection,
             connected_connection_euque: VecDeque::new(),
             listening_socket: None,
-            sender,
-        })
-    }
-
-    pub fn send_tcp_packet(
-        &mut self,
-        seq: u32,
-        ack: u32,
-        flag: u8,
-        payload: &[u8],
-    ) -> Result<usize> {
-        let mut tcp_packet = TCPPacket::new(payload.len());
-        tcp_packet.set_src(self.local_port);
-        tcp_packet.set_dest(self.remote_port);
-        tcp_packet.set_seq(seq);
-        tcp_packet.set_ack(ack);
-        // NOTE: 今回はオプションフィールドは使わない。
-        // NOTE: よって、ヘッダーは 32-bit words * 5 分あることになり、その直後に data(payload) が始まることになる。
-        // NOTE: よって、data offset は 5 になる。詳しくは[RFC9293](https://datatracker.ietf.org/doc/html/rfc9293)を参照。
-        tcp_packet.set_data_offset(5);
-        tcp_packet.set_flag(flag);
-        tcp_packet.set_window_size(self.recv_param.window);
-        tcp_packet.set_payload(payload);
-        tcp_packet.set_checksum(util::ipv4_checksum(
-            &tcp_packet.packet(),
-            8,
-            &[],
-            &self.local_addr,
-            &self.remote_addr,
-            IpNextHeaderProtocols::Tcp,
-        ));
-        let sent_size = self
-            .sender
-            .send_to(tcp_packet.clone(), IpAddr::V4(self.remote_addr))
-            .context(format!("failed to send: \n{:?}", tcp_packet))?;
-
-        dbg!("sent", &tcp_packet);
-        // もし送信先から確認応答がこなかった場合は再送する必要がある。
-        // なので、送信直後のこのタイミングでエンキューする。
-        // ただし、ペイロードを持たないACKセグメントは再送対象にはなりません。ACKセグメントのACKセグメントというように、無限に確認応答が必要になる。
-        // 再送対象になるのは、ペイロードが存在しているか、ACKセグメントでないセグメントです。
-        // 例：SYNセグメント、SYN|ACKセグメント、ペイロードをのせたACKセグメント
-        if payload.is_empty() && tcp_packet.get_flag() == tcpflags::ACK {
-            return Ok(sent_size);
         }
-        self.retransmission_queue
-            .push_back(RetransmissionQueueEntry::new(tcp_packet));
-        Ok(sent_size)
     }
 
     pub fn get_sock_id(&self) -> SockID {
-        SockID(
-            self.local_addr,
-            self.remote_addr,
-            self.local_port,
-            self.remote_port,
-        )
+        self.connection.get_sock_id()
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct RetransmissionQueueEntry {
     pub packet: TCPPacket,
-    pub latest_transmission_time: SystemTime,
+    pub first_transmission_time: Duration, // 最初に送信した時刻 (Clock::now の値)
+    pub latest_transmission_time: Duration, // 最後に送信した時刻 (Clock::now の値)
     pub transmission_count: u8,
+    pub sacked: bool, // 受信側に届いたことが SACK で伝えられた
 }
 
 impl RetransmissionQueueEntry {
-    fn new(packet: TCPPacket) -> Self {
+    pub fn new(packet: TCPPacket, now: Duration) -> Self {
         Self {
             packet,
-            latest_transmission_time: SystemTime::now(),
+            first_transmission_time: now,
+            latest_transmission_time: now,
             transmission_count: 1,
+            sacked: false,
         }
     }
 }
@@ -1,42 +1,73 @@
+use crate::clock::{Clock, SystemClock};
+use crate::congestion::CongestionAlgorithm;
+use crate::connection::{
+    self, AcceptOutcome, Connection, TCPEventKind, DEFAULT_MSL, DEFAULT_USER_TIMEOUT,
+    SOCKET_BUFFER_SIZE,
+};
+use crate::link::Link;
 use crate::packet::TCPPacket;
-use crate::socket::{SockID, Socket, TcpStatus};
-use crate::tcpflags;
+use crate::seqnum::SeqNum;
+use crate::socket::{SockID, Socket, SocketStats, TcpStatus};
 use anyhow::{Context, Result};
-use pnet::packet::{ip::IpNextHeaderProtocols, tcp::TcpPacket, Packet};
-use pnet::transport::{self, TransportChannelType};
+use pnet::packet::{tcp::TcpPacket, Packet};
 use rand::{rngs::ThreadRng, Rng};
-use std::collections::HashMap;
-use std::net::{IpAddr, Ipv4Addr};
-use std::process::Command;
-use std::sync::{Arc, Condvar, Mutex, RwLock, RwLockWriteGuard};
-use std::time::{Duration, SystemTime};
-use std::{cmp, ops::Range, str, thread};
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::ops::Range;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::Duration;
 
 const UNDETERMINED_IP_ADDR: std::net::Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
 const UNDETERMINED_PORT: u16 = 0;
-const MAX_TRANSMITTION: u8 = 5;
-// RFCでは動的にタイムアウトを設定する方法について記載しているが、ここでは定数とする。
-const RETRANSMITTION_TIMEOUT: u64 = 3;
-const MSS: usize = 1460;
+// タイマースレッドが再送キューを確認する間隔
+const TIMER_INTERVAL: Duration = Duration::from_millis(100);
 const PORT_RANGE: Range<u16> = 40000..60000;
 
+/// `Connection` をスレッドから使うためのドライバ。
+/// 受信スレッドとタイマースレッドがコネクションに入力を渡し、出力されたセグメントをリンクに送信して、待機中の呼び出し元をイベントで起こす。
 pub struct TCP {
     // TCP 全体の管理を3つのスレッドから扱うため。
     sockets: RwLock<HashMap<SockID, Socket>>,
-    event_condvar: (Mutex<Option<TCPEvent>>, Condvar),
+    // NOTE: 発行済みでまだ待機側に消費されていないイベントの集合。
+    // NOTE: 1つのスロットだと、待機側が起きる前に別のイベントで上書きされて取りこぼすことがあるため。
+    event_condvar: (Mutex<HashSet<TCPEvent>>, Condvar),
+    // セグメントの送受信に使うバックエンド
+    link: Arc<dyn Link>,
+    // タイマーが参照する時計
+    clock: Arc<dyn Clock>,
+    // 新しく生成するコネクションの MSL
+    msl: RwLock<Duration>,
+    // 新しく生成するコネクションのユーザータイムアウト
+    user_timeout: RwLock<Duration>,
+    // 新しく生成するコネクションの送信バッファと受信バッファのバイト数
+    send_buffer_size: RwLock<usize>,
+    recv_buffer_size: RwLock<usize>,
 }
 
 impl TCP {
-    pub fn new() -> Arc<Self> {
+    pub fn new(link: Arc<dyn Link>) -> Arc<Self> {
+        Self::with_clock(link, Arc::new(SystemClock::new()))
+    }
+
+    /// タイマーが参照する時計を指定して TCP を生成する。
+    pub fn with_clock(link: Arc<dyn Link>, clock: Arc<dyn Clock>) -> Arc<Self> {
         let sockets = RwLock::new(HashMap::new());
         let tcp = Arc::new(Self {
             sockets,
-            event_condvar: (Mutex::new(None), Condvar::new()),
+            event_condvar: (Mutex::new(HashSet::new()), Condvar::new()),
+            link,
+            clock,
+            msl: RwLock::new(DEFAULT_MSL),
+            user_timeout: RwLock::new(DEFAULT_USER_TIMEOUT),
+            send_buffer_size: RwLock::new(SOCKET_BUFFER_SIZE),
+            recv_buffer_size: RwLock::new(SOCKET_BUFFER_SIZE),
         });
         let cloned_tcp = tcp.clone();
         std::thread::spawn(move || {
             // パケットの受信用スレッド
-            cloned_tcp.receive_handler().unwrap();
+            if let Err(error) = cloned_tcp.receive_handler() {
+                dbg!(error);
+            }
         });
         let cloned_tcp = tcp.clone();
         std::thread::spawn(move || {
@@ -46,6 +77,61 @@ impl TCP {
         tcp
     }
 
+    /// 以降に生成するソケットの MSL を設定する。アクティブクローズしたソケットは、この2倍の時間 TIMEWAIT 状態に留まる。
+    pub fn set_msl(&self, msl: Duration) {
+        *self.msl.write().unwrap() = msl;
+    }
+
+    /// 以降に生成するソケットのユーザータイムアウトを設定する。
+    /// 送信したセグメントがこの時間 ack されなければ接続を中断し、send/recv などは TimedOut エラーを返す。
+    pub fn set_user_timeout(&self, user_timeout: Duration) {
+        *self.user_timeout.write().unwrap() = user_timeout;
+    }
+
+    /// 以降に生成するソケットの送信バッファのバイト数を設定する。ack されていないデータはこの大きさまでしか送信しない。
+    pub fn set_send_buffer_size(&self, size: usize) {
+        *self.send_buffer_size.write().unwrap() = size;
+    }
+
+    /// 以降に生成するソケットの受信バッファのバイト数を設定する。
+    /// 64KB を超える分は、ハンドシェイクでウィンドウスケールを合意できた場合に使われる。最大は MAX_SOCKET_BUFFER_SIZE。
+    pub fn set_recv_buffer_size(&self, size: usize) {
+        *self.recv_buffer_size.write().unwrap() = size;
+    }
+
+    /// ソケットの輻輳制御アルゴリズムを切り替える。
+    /// リスニングソケットに設定すると、そこから accept した接続済みソケットにも引き継がれる。
+    pub fn set_congestion_control(
+        &self,
+        sock_id: SockID,
+        algorithm: CongestionAlgorithm,
+    ) -> Result<()> {
+        let mut table = self.sockets.write().unwrap();
+        let socket = table
+            .get_mut(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        socket.connection.set_congestion_control(algorithm);
+        Ok(())
+    }
+
+    /// ソケットの状態を返す。
+    pub fn status(&self, sock_id: SockID) -> Result<TcpStatus> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        Ok(socket.connection.status.clone())
+    }
+
+    /// ソケットの統計情報を返す。
+    pub fn stats(&self, sock_id: SockID) -> Result<SocketStats> {
+        let table = self.sockets.read().unwrap();
+        let socket = table
+            .get(&sock_id)
+            .context(format!("no such socket: {:?}", sock_id))?;
+        Ok(socket.connection.stats.clone())
+    }
+
     fn select_unused_port(&self, rng: &mut ThreadRng) -> Result<u16> {
         for _ in 0..(PORT_RANGE.end - PORT_RANGE.start) {
             let local_port = rng.gen_range(PORT_RANGE);
@@ -60,51 +146,51 @@ impl TCP {
 
     /// ターゲットに接続し、接続済みソケットIDを返す。
     pub fn connect(&self, addr: Ipv4Addr, port: u16) -> Result<SockID> {
-        let mut rng = rand::thread_rng();
-        let mut socket = Socket::new(
-            get_source_addr_to(addr)?,
-            addr,
-            self.select_unused_port(&mut rng)?,
-            port,
-            TcpStatus::SynSent,
-        )?;
-
-        socket.send_param.initial_seq = rng.gen_range(1..1 << 31);
-        // ここで SYN を送ってる。3 way handshake の最初のセグメント。
-        socket.send_tcp_packet(socket.send_param.initial_seq, 0, tcpflags::SYN, &[])?;
-        socket.send_param.unacked_seq = socket.send_param.initial_seq;
-        // NOTE: SYN セグメントはペイロードを持たないが、確認応答を受け取るために1つインクリメントする。FIN セグメントも同様。
-        socket.send_param.next = socket.send_param.initial_seq + 1;
+        let local_port = self.select_unused_port(&mut rand::thread_rng())?;
+        self.connect_from(local_port, addr, port)
+    }
+
+    /// 送信元ポートを指定してターゲットに接続し、接続済みソケットIDを返す。
+    /// 相手も同時にこちらへ接続してきた場合は、同時オープンで1つのコネクションになる。
+    pub fn connect_from(&self, local_port: u16, addr: Ipv4Addr, port: u16) -> Result<SockID> {
+        let local_addr = self.link.source_addr_to(addr)?;
+        let mut connection =
+            Connection::new(local_addr, addr, local_port, port, TcpStatus::SynSent);
+        connection.msl = *self.msl.read().unwrap();
+        connection.user_timeout = *self.user_timeout.read().unwrap();
+        connection.local_mss = Connection::mss_for_mtu(self.link.mtu(local_addr)?);
+        connection.send_buffer_size = *self.send_buffer_size.read().unwrap();
+        connection.set_recv_buffer_size(*self.recv_buffer_size.read().unwrap());
+        connection.open(SeqNum::new(rand::thread_rng().gen()), self.clock.now());
         let mut table = self.sockets.write().unwrap();
-        let sock_id = socket.get_sock_id();
-        table.insert(sock_id, socket);
+        let sock_id = connection.get_sock_id();
+        if table.contains_key(&sock_id) {
+            // TIMEWAIT のソケットも含めて、同じ SockID のコネクションは作らない
+            anyhow::bail!("address already in use: {:?}", sock_id);
+        }
+        table.insert(sock_id, Socket::new(connection));
+        self.flush(&mut table, sock_id)?;
 
         // NOTE: ロックを外してイベントの待機. 受信スレッドがロックを取得できるようにするため。
         drop(table);
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
-
+        if !self.wait_event(sock_id, TCPEventKind::ConnectionCompleted) {
+            let mut table = self.sockets.write().unwrap();
+            let error = table[&sock_id].connection.error.unwrap();
+            // RST で拒否されたソケットは残しておいても使えないので破棄する
+            self.remove_socket(&mut table, sock_id);
+            return Err(error.into());
+        }
         Ok(sock_id)
     }
 
     /// 受信スレッド用のメソッド
     fn receive_handler(&self) -> Result<()> {
         dbg!("begin recv thread");
-        let (_, mut receiver) = transport::transport_channel(
-            65535,
-            // NOTE: IPアドレスが必要なので、IPパケットレベルで取得.
-            TransportChannelType::Layer3(IpNextHeaderProtocols::Tcp),
-        )
-        .unwrap();
-        // NOTE: このイテレータに対して`next()`を呼び出すと、パケットを受信するまでスレッドをブロックして待機します。
-        let mut packet_iter = transport::ipv4_packet_iter(&mut receiver);
         loop {
-            let (packet, remote_addr) = match packet_iter.next() {
-                Ok((p, r)) => (p, r),
-                Err(_) => continue,
-            };
-            let local_addr = packet.get_destination();
+            // NOTE: セグメントを受信するまでスレッドをブロックして待機します。
+            let (remote_addr, local_addr, segment) = self.link.recv()?;
             // pnet の TcpPacket を生成
-            let tcp_packet = match TcpPacket::new(packet.payload()) {
+            let tcp_packet = match TcpPacket::new(&segment) {
                 Some(p) => p,
                 None => {
                     continue;
@@ -112,12 +198,10 @@ impl TCP {
             };
             // pnet の TcpPacket から tcp::TCPPacket に変換する
             let packet = TCPPacket::from(tcp_packet);
-            let remote_addr = match remote_addr {
-                IpAddr::V4(addr) => addr,
-                _ => {
-                    continue;
-                }
-            };
+            if !packet.has_valid_data_offset() {
+                dbg!("invalid data offset");
+                continue;
+            }
             let mut table = self.sockets.write().unwrap();
             let socket = match table.get_mut(&SockID(
                 local_addr,
@@ -133,7 +217,14 @@ impl TCP {
                     UNDETERMINED_PORT,
                 )) {
                     Some(socket) => socket, // リスニングソケット（とは？）
-                    None => continue,       // どのソケットにも該当しないものは無視
+                    None => {
+                        // どのソケットにも該当しないものには RST を返す
+                        drop(table);
+                        if let Err(error) = self.reset_handler(&packet, local_addr, remote_addr) {
+                            dbg!(error);
+                        }
+                        continue;
+                    }
                 },
             };
 
@@ -143,247 +234,202 @@ impl TCP {
             }
 
             let sock_id = socket.get_sock_id();
-
-            if let Err(error) = match socket.status {
-                TcpStatus::Listen => self.listen_handler(table, sock_id, &packet, remote_addr),
-                TcpStatus::SynRcvd => self.synrcvd_handler(table, sock_id, &packet),
-                // SYN を受け取ったということなので、応答をする必要がある。
-                TcpStatus::SynSent => self.synsent_handler(socket, &packet),
-                TcpStatus::Established => self.established_handler(socket, &packet),
-                TcpStatus::CloseWait | TcpStatus::LastAck => self.close_handler(socket, &packet),
-                TcpStatus::FinWait1 | TcpStatus::FinWait2 => self.finwait_handler(socket, &packet),
-                _ => {
-                    dbg!("not implemented state");
-                    Ok(())
+            let now = self.clock.now();
+            let sock_id = if socket.connection.status == TcpStatus::Listen {
+                match self.listen_handler(&mut table, sock_id, &packet, remote_addr) {
+                    Ok(Some(sock_id)) => sock_id,
+                    Ok(None) => continue,
+                    Err(error) => {
+                        dbg!(error);
+                        continue;
+                    }
                 }
-            } {
-                dbg!(error);
-            }
-        }
-    }
-
-    fn delete_acked_segment_from_retransmission_queue(&self, socket: &mut Socket) {
-        dbg!("ack accept", socket.send_param.unacked_seq);
-        while let Some(item) = socket.retransmission_queue.pop_front() {
-            // Question: ここは、`>=`じゃダメなのだろうか？
-            if socket.send_param.unacked_seq > item.packet.get_seq() {
-                dbg!("successfully acked", item.packet.get_seq());
-                socket.send_param.window += item.packet.payload().len() as u16;
-                self.publish_event(socket.get_sock_id(), TCPEventKind::Acked);
             } else {
-                // ack されていない。戻す。
-                socket.retransmission_queue.push_front(item);
-                break;
+                socket.connection.handle_segment(&packet, now);
+                sock_id
+            };
+            if let Err(error) = self.flush(&mut table, sock_id) {
+                dbg!(error);
             }
         }
     }
 
-    /// ESTABLISHED 状態のソケットに到着したパケットの処理
-    fn established_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("established handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            // 未送信セグメントに対するackは破棄
-            return Ok(());
-        }
-
-        if packet.get_flag() & tcpflags::ACK == 0 {
-            // ACKが経っていないパケットは破棄
-            return Ok(());
-        }
-
-        if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
-        }
-
-        // パッシブクローズの処理
-        // ESTABLISHED状態の時に FIN|ACK を相手から受け取ることになるので、ここに処理を書きます。
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            socket.recv_param.next = packet.get_seq() + 1;
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &[],
-            )?;
-            socket.status = TcpStatus::CloseWait;
-            self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
-        }
-
-        Ok(())
-    }
-
     /// LISTEN状態のソケットに到着したパケットの処理
+    /// パッシブオープンした場合は、新しく生成したソケットのIDを返す。
     fn listen_handler(
         &self,
-        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
+        table: &mut HashMap<SockID, Socket>,
         listening_socket_id: SockID,
         packet: &TCPPacket,
         remote_addr: Ipv4Addr,
-    ) -> Result<()> {
-        dbg!("listen handler");
-        if packet.get_flag() & tcpflags::ACK > 0 {
-            // NOTE: 本来ならRSTをsendする
-            return Ok(());
-        }
-
+    ) -> Result<Option<SockID>> {
         let listening_socket = table.get_mut(&listening_socket_id).unwrap();
-
-        if packet.get_flag() & tcpflags::SYN > 0 {
-            // passive open の処理
-            // 後に接続済みソケットとなるソケットを新たに生成する
-            let mut connection_socket = Socket::new(
-                listening_socket.local_addr,
-                remote_addr,
-                listening_socket.local_port,
-                packet.get_src(),
-                TcpStatus::SynRcvd,
-            )?;
-            connection_socket.recv_param.next = packet.get_seq() + 1;
-            connection_socket.recv_param.initial_seq = packet.get_seq();
-            connection_socket.send_param.initial_seq = rand::thread_rng().gen_range(1..1 << 31);
-            connection_socket.send_param.window = packet.get_window_size();
-            // 応答したメッセージを返している。
-            connection_socket.send_tcp_packet(
-                connection_socket.send_param.initial_seq,
-                connection_socket.recv_param.next,
-                tcpflags::SYN | tcpflags::ACK,
-                &[],
-            )?;
-            connection_socket.send_param.next = connection_socket.send_param.initial_seq + 1;
-            connection_socket.send_param.unacked_seq = connection_socket.send_param.initial_seq;
-            connection_socket.listening_socket = Some(listening_socket.get_sock_id());
-            dbg!("status: listen -> ", &connection_socket.status);
-            table.insert(connection_socket.get_sock_id(), connection_socket);
+        match listening_socket.connection.accept(
+            remote_addr,
+            packet,
+            SeqNum::new(rand::thread_rng().gen()),
+            self.clock.now(),
+        ) {
+            AcceptOutcome::Accepted(connection) => {
+                let mut connection_socket = Socket::new(*connection);
+                connection_socket.listening_socket = Some(listening_socket_id);
+                let sock_id = connection_socket.get_sock_id();
+                table.insert(sock_id, connection_socket);
+                Ok(Some(sock_id))
+            }
+            AcceptOutcome::Reset(rst) => {
+                self.send_segment(&rst, remote_addr)?;
+                Ok(None)
+            }
+            AcceptOutcome::Discarded => Ok(None),
         }
-        Ok(())
     }
 
-    /// SYNRCVD 状態のソケットに到着したパケットの処理
-    fn synrcvd_handler(
+    /// どのソケットにも該当しないセグメントの処理
+    /// 存在しないコネクションへのセグメントには RST を返して、相手に接続がないことを知らせる。
+    fn reset_handler(
         &self,
-        mut table: RwLockWriteGuard<HashMap<SockID, Socket>>,
-        sock_id: SockID,
         packet: &TCPPacket,
+        local_addr: Ipv4Addr,
+        remote_addr: Ipv4Addr,
     ) -> Result<()> {
-        dbg!("synrcvd handler");
-        let socket = table.get_mut(&sock_id).unwrap();
-
-        if packet.get_flag() & tcpflags::ACK > 0
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.recv_param.next = packet.get_seq();
-            socket.send_param.unacked_seq = packet.get_ack();
-            socket.status = TcpStatus::Established;
-            dbg!("status: synrcvd -> ", &socket.status);
-            if let Some(id) = socket.listening_socket {
-                let ls = table.get_mut(&id).unwrap();
-                ls.connected_connection_euque.push_back(sock_id);
-                self.publish_event(ls.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            }
+        if !packet.is_correct_checksum(local_addr, remote_addr) {
+            dbg!("invalid checksum");
+            return Ok(());
+        }
+        if let Some(rst) = connection::reset_segment(local_addr, remote_addr, packet) {
+            self.send_segment(&rst, remote_addr)?;
         }
+        Ok(())
+    }
 
+    fn send_segment(&self, packet: &TCPPacket, remote_addr: Ipv4Addr) -> Result<()> {
+        self.link
+            .send_to(packet.packet(), remote_addr)
+            .context(format!("failed to send: \n{:?}", packet))?;
+        dbg!("sent", &packet);
         Ok(())
     }
 
-    /// SYNSENT 状態のソケットに到着したパケットの処理
-    /// NOTE: SYN を送信した後なので、相手からSYN|ACKセグメントを受け取ればコネクションが確立され、アクティブオープンが成功.
-    fn synsent_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("synsent handler");
-
-        // NOTE: ここの`if`は、TCPにおけるセグメントの受診時全般に当てはまる条件を述べています。
-        // NOTE: ACK ビットは基本的にONになっている必要がある。例外はソケットがLISTEN状態の時。
-        if packet.get_flag() & tcpflags::ACK > 0
-            // NOTE: `socket.send_param.unacked_seq <= packet.get_ack() <= socket.send_param.next`: セグメントが運んでくる確認応答番号は正しい範囲内に含まれる必要があります。
-            && socket.send_param.unacked_seq <= packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-            && packet.get_flag() & tcpflags::SYN > 0
-        {
-            socket.recv_param.next = packet.get_seq() + 1;
-            socket.recv_param.initial_seq = packet.get_seq();
-            socket.send_param.unacked_seq = packet.get_ack();
-            socket.send_param.window = packet.get_window_size();
-
-            // TODO: この条件で Established になるのってなんでだっけ？
-            // 図3.4を見たらそうなんだけど、コードのどこでunacked_seqが更新されていくのか？
-            if socket.send_param.unacked_seq > socket.send_param.initial_seq {
-                socket.status = TcpStatus::Established;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsent ->", &socket.status);
-                self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionCompleted);
-            } else {
-                socket.status = TcpStatus::SynRcvd;
-                socket.send_tcp_packet(
-                    socket.send_param.next,
-                    socket.recv_param.next,
-                    tcpflags::ACK,
-                    &[],
-                )?;
-                dbg!("status: synsent ->", &socket.status);
+    /// コネクションが出力したセグメントを送信し、イベントを発行する。
+    fn flush(&self, table: &mut HashMap<SockID, Socket>, sock_id: SockID) -> Result<()> {
+        let socket = match table.get_mut(&sock_id) {
+            Some(socket) => socket,
+            None => return Ok(()),
+        };
+        while let Some(packet) = socket.connection.poll_transmit() {
+            self.send_segment(&packet, socket.connection.remote_addr)?;
+        }
+
+        let mut completed = false;
+        while let Some(kind) = socket.connection.poll_event() {
+            completed |= kind == TCPEventKind::ConnectionCompleted;
+            self.publish_event(sock_id, kind);
+        }
+        let closed = socket.connection.status == TcpStatus::Closed;
+        let listening_socket = socket.listening_socket;
+        // パッシブオープンで確立した接続は、生成元のリスニングソケットの accept に渡す
+        if let (true, Some(id)) = (completed, listening_socket) {
+            if let Some(ls) = table.get_mut(&id) {
+                ls.connected_connection_euque.push_back(sock_id);
+                self.publish_event(id, TCPEventKind::ConnectionCompleted);
+            }
+        }
+        // 確立する前に RST を受け取ったパッシブオープンの接続は、LISTEN に戻ったものとして破棄する
+        if let (true, Some(id)) = (closed, listening_socket) {
+            if !table
+                .get(&id)
+                .is_some_and(|ls| ls.connected_connection_euque.contains(&sock_id))
+            {
+                self.remove_socket(table, sock_id);
             }
         }
         Ok(())
     }
 
-    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) {
+    /// ソケットを破棄し、消費されずに残っているイベントも削除する。
+    fn remove_socket(&self, table: &mut HashMap<SockID, Socket>, sock_id: SockID) {
+        table.remove(&sock_id);
+        let (lock, _) = &self.event_condvar;
+        lock.lock()
+            .unwrap()
+            .retain(|event| event.sock_id != sock_id);
+        dbg!("closed & removed", sock_id);
+    }
+
+    /// イベントが発行されるまで待機する。
+    /// 待っていたイベントを消費できずに、ソケットの異常終了で起こされた場合は false を返す。
+    fn wait_event(&self, sock_id: SockID, kind: TCPEventKind) -> bool {
         let (lock, cvar) = &self.event_condvar;
-        let mut event = lock.lock().unwrap();
-        loop {
-            if let Some(ref e) = *event {
-                if e.sock_id == sock_id && e.kind == kind {
-                    break;
-                }
+        let mut events = lock.lock().unwrap();
+        let event = TCPEvent::new(sock_id, kind);
+        let aborted = TCPEvent::new(sock_id, TCPEventKind::ConnectionAborted);
+        // 対象のイベントが発行済みなら消費して戻る
+        while !events.remove(&event) {
+            if events.contains(&aborted) {
+                // 異常終了したソケットを待っている呼び出しは全て起こす。エラーは呼び出し側で確認する。
+                dbg!(&aborted);
+                return false;
             }
-            // cvar が nofity されるまで event のロックを外して待機
-            event = cvar.wait(event).unwrap();
+            // cvar が nofity されるまで events のロックを外して待機
+            events = cvar.wait(events).unwrap();
         }
         dbg!(&event);
-        *event = None;
+        true
     }
 
     /// 指定のソケットIDにイベントを発行する
     fn publish_event(&self, sock_id: SockID, kind: TCPEventKind) {
         let (lock, cvar) = &self.event_condvar;
-        let mut e = lock.lock().unwrap();
-        *e = Some(TCPEvent::new(sock_id, kind));
+        let mut events = lock.lock().unwrap();
+        events.insert(TCPEvent::new(sock_id, kind));
         cvar.notify_all();
     }
 
     /// リスニングソケットを生成してソケットIDを返す
     pub fn listen(&self, local_addr: Ipv4Addr, local_port: u16) -> Result<SockID> {
-        let socket = Socket::new(
+        let mut connection = Connection::new(
             local_addr,
             UNDETERMINED_IP_ADDR, // まだ接続先IPアドレスは未定
             local_port,
             UNDETERMINED_PORT, // まだ接続先ポート番号は未定
             TcpStatus::Listen,
-        )?;
+        );
+        // パッシブオープンしたコネクションはリスニングソケットの設定を引き継ぐ
+        connection.msl = *self.msl.read().unwrap();
+        connection.user_timeout = *self.user_timeout.read().unwrap();
+        connection.local_mss = Connection::mss_for_mtu(self.link.mtu(local_addr)?);
+        connection.send_buffer_size = *self.send_buffer_size.read().unwrap();
+        connection.set_recv_buffer_size(*self.recv_buffer_size.read().unwrap());
         let mut lock = self.sockets.write().unwrap();
-        let sock_id = socket.get_sock_id();
-        lock.insert(sock_id, socket);
+        let sock_id = connection.get_sock_id();
+        lock.insert(sock_id, Socket::new(connection));
         Ok(sock_id)
     }
 
     /// 接続済みソケットが生成されるまで待機し、生成されたらそのIDを返す。
     pub fn accept(&self, sock_id: SockID) -> Result<SockID> {
-        self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
-        let mut table = self.sockets.write().unwrap();
-        Ok(table
-            .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?
-            .connected_connection_euque
-            .pop_front()
-            .context("no connected socket")?)
+        loop {
+            let mut table = self.sockets.write().unwrap();
+            // accept を呼ぶ前に確立済みの接続があれば、待たずに返す
+            if let Some(connected_socket) = table
+                .get_mut(&sock_id)
+                .context(format!("no such socket: {:?}", sock_id))?
+                .connected_connection_euque
+                .pop_front()
+            {
+                let socket = table.get_mut(&connected_socket).unwrap();
+                socket.listening_socket = None;
+                if let Some(error) = socket.connection.error {
+                    // accept される前に RST で切断された
+                    self.remove_socket(&mut table, connected_socket);
+                    return Err(error.into());
+                }
+                return Ok(connected_socket);
+            }
+            drop(table);
+            self.wait_event(sock_id, TCPEventKind::ConnectionCompleted);
+        }
     }
 
     /// バッファのデータを順番に送信する。
@@ -392,44 +438,26 @@ impl TCP {
         let mut cursor = 0;
         while cursor < buffer.len() {
             let mut table = self.sockets.write().unwrap();
-            let mut socket = table
+            let socket = table
                 .get_mut(&sock_id)
                 .context(format!("no such socket: {:?}", sock_id))?;
-            //let send_size = cmp::min(MSS, buffer.len() - cursor);
-            let mut send_size = cmp::min(
-                MSS,
-                cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
-            );
-            while send_size == 0 {
-                dbg!("unable to slide send window");
-                // ロックを外してイベントの待機。受診スレッドがロックを取得できるようにするため。
-                drop(table);
+            let now = self.clock.now();
+            let sent_size = socket.connection.send(&buffer[cursor..], now)?;
+            let pacing_delay = socket.connection.pacing_delay(now);
+            self.flush(&mut table, sock_id)?;
+            // ロックを外して待機して、受信スレッドがACKを受信できるようにしている。
+            drop(table);
+            cursor += sent_size;
+            if cursor == buffer.len() {
+                break;
+            }
+            if !pacing_delay.is_zero() {
+                // ペーシングで、次のセグメントを送信できる時刻まで待機する
+                self.clock.sleep(pacing_delay);
+            } else if sent_size == 0 {
+                // 送信ウィンドウが空くまで待機する
                 self.wait_event(sock_id, TCPEventKind::Acked);
-                table = self.sockets.write().unwrap();
-                socket = table
-                    .get_mut(&sock_id)
-                    .context(format!("no such socket: {:?}", sock_id))?;
-                // 送信サイズを再計算する
-                send_size = cmp::min(
-                    MSS,
-                    cmp::min(socket.send_param.window as usize, buffer.len() - cursor),
-                );
             }
-            dbg!("current window size", socket.send_param.window);
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK, // 接続済みの場合はずっと ACK フラグは立てておくのか。
-                &buffer[cursor..cursor + send_size],
-            )?;
-            cursor += send_size;
-            socket.send_param.next += send_size as u32;
-            // window をスライドさせる（見た目的には window size を減らしているように見えるが、ずらしてるだけ）
-            socket.send_param.window -= send_size as u16;
-            // 少しの間ロックを外して待機して、受信スレッドがACKを受信できるようにしている。
-            // send_window が0になるまで送り続け、送信がブロックされる確率を下げるため。
-            drop(table);
-            thread::sleep(Duration::from_millis(1));
         }
         Ok(())
     }
@@ -440,140 +468,47 @@ impl TCP {
         dbg!("begin timer thread");
         loop {
             let mut table = self.sockets.write().unwrap();
+            let now = self.clock.now();
             // 全てのソケットを順次見ていく
-            for (sock_id, socket) in table.iter_mut() {
-                while let Some(mut item) = socket.retransmission_queue.pop_front() {
-                    // 再送キューから ack されたセグメントを除去する。
-                    // established state 以外の時に送信されたセグメントを除去するために必要
-                    if socket.send_param.unacked_seq > item.packet.get_seq() {
-                        dbg!("successfully acked", item.packet.get_seq());
-                        // window を右にずらしている。
-                        socket.send_param.window += item.packet.payload().len() as u16;
-                        self.publish_event(*sock_id, TCPEventKind::Acked);
-
-                        // FIN|ACK セグメントが確認応答されたことをタイマースレッドでチェックして
-                        // ConnectionClosed イベントを投げます。
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && socket.status == TcpStatus::LastAck
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
-
-                        continue;
-                    }
-
-                    // timeout を確認
-                    if item.latest_transmission_time.elapsed().unwrap()
-                        < Duration::from_secs(RETRANSMITTION_TIMEOUT)
-                    {
-                        // timeout していないので再送キューに戻す
-                        // この時、これ以降のエントリもタイムアウトしていないと判断できるので、先頭に戻す。
-                        socket.retransmission_queue.push_front(item);
-                        break;
-                    }
-
-                    // ack されていなければ再送
-                    if item.transmission_count < MAX_TRANSMITTION {
-                        dbg!("retransmit");
-                        socket
-                            .sender
-                            .send_to(item.packet.clone(), IpAddr::V4(socket.remote_addr))
-                            .context("failed to retransmit")
-                            .unwrap();
-                        item.transmission_count += 1;
-                        item.latest_transmission_time = SystemTime::now();
-                        socket.retransmission_queue.push_back(item);
-                        break;
-                    } else {
-                        dbg!("reached MAX_TRANSMITTION");
-                        // FIN|ACKセグメントを最大まで再送しても返事が返ってこない場合は、
-                        // 相手が勝手に終了している可能性があるため、その場合も ConnectionClosed イベントを投げる。
-                        if item.packet.get_flag() & tcpflags::FIN > 0
-                            && (socket.status == TcpStatus::LastAck
-                                || socket.status == TcpStatus::FinWait1
-                                || socket.status == TcpStatus::FinWait2)
-                        {
-                            self.publish_event(*sock_id, TCPEventKind::ConnectionClosed);
-                        }
-                    }
+            let sock_ids: Vec<SockID> = table.keys().cloned().collect();
+            for sock_id in sock_ids {
+                let connection = &mut table.get_mut(&sock_id).unwrap().connection;
+                let time_wait = connection.status == TcpStatus::TimeWait;
+                connection.handle_timeout(now);
+                if time_wait && connection.status == TcpStatus::Closed {
+                    // TIMEWAIT を抜けたソケットは、close は既に戻っているのでここで破棄する
+                    self.remove_socket(&mut table, sock_id);
+                    continue;
+                }
+                if let Err(error) = self.flush(&mut table, sock_id) {
+                    dbg!(error);
                 }
             }
             // write lock を外して待機する
             drop(table);
-            thread::sleep(Duration::from_millis(100));
+            self.clock.sleep(TIMER_INTERVAL);
         }
     }
 
     /// データをバッファに読み込んで、読み込んだサイズを返す。FINを読み込んだ場合は0を返す。
     pub fn recv(&self, sock_id: SockID, buffer: &mut [u8]) -> Result<usize> {
-        let mut table = self.sockets.write().unwrap();
-        let mut socket = table
-            .get_mut(&sock_id)
-            .context(format!("no such socket: {:?}", sock_id))?;
-        let mut received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
-
-        // ここのループで、読み込むデータサイズを決定する。
-        while received_size == 0 {
-            // ペイロードを受信 or FIN を受信でスキップ
-            match socket.status {
-                TcpStatus::CloseWait | TcpStatus::LastAck | TcpStatus::TimeWait => break,
-                _ => {}
+        loop {
+            let mut table = self.sockets.write().unwrap();
+            let socket = table
+                .get_mut(&sock_id)
+                .context(format!("no such socket: {:?}", sock_id))?;
+            let received = socket.connection.recv(buffer, self.clock.now())?;
+            // 受信ウィンドウが開いたことを伝える ACK を送る
+            self.flush(&mut table, sock_id)?;
+            if let Some(size) = received {
+                return Ok(size);
             }
 
             // lock を外してイベントの待機。受診スレッドがロックを取得できるようにするため。
             drop(table);
             dbg!("waiting incoming data");
             self.wait_event(sock_id, TCPEventKind::DataArrived);
-            table = self.sockets.write().unwrap();
-            socket = table
-                .get_mut(&sock_id)
-                .context(format!("no such socket: {:?}", sock_id))?;
-            received_size = socket.recv_buffer.len() - socket.recv_param.window as usize;
         }
-        let copy_size = cmp::min(buffer.len(), received_size);
-
-        // バッファーにデータを読み込む！
-        buffer[..copy_size].copy_from_slice(&socket.recv_buffer[..copy_size]);
-
-        // 読み込まなかった残りの分を先頭に移動させる。
-        socket.recv_buffer.copy_within(copy_size.., 0);
-        socket.recv_param.window += copy_size as u16;
-        Ok(copy_size)
-    }
-
-    /// パケットのペイロードを受信バッファにコピーする
-    fn process_payload(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        // バッファにおける読み込みヘッドの位置
-        let offset = socket.recv_buffer.len() - socket.recv_param.window as usize
-            + (packet.get_seq() - socket.recv_param.next) as usize;
-        let copy_size = cmp::min(packet.payload().len(), socket.recv_buffer.len() - offset);
-        socket.recv_buffer[offset..offset + copy_size]
-            .copy_from_slice(&packet.payload()[..copy_size]);
-        // ロス再送の際、穴埋めされるためにmaxをとる
-        socket.recv_param.tail =
-            cmp::max(socket.recv_param.tail, packet.get_seq() + copy_size as u32);
-
-        if packet.get_seq() == socket.recv_param.next {
-            // 順序入れ替わり無しの場合のみ、recv_param.next を進める
-            socket.recv_param.next = socket.recv_param.tail;
-            socket.recv_param.window -= (socket.recv_param.tail - packet.get_seq()) as u16;
-        }
-
-        if copy_size > 0 {
-            // 受信バッファにコピーが成功
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                // 受け取りが成功したので、ACKで返すってことね。
-                tcpflags::ACK,
-                &[],
-            )?;
-        } else {
-            // 受信バッファが溢れたときはセグメントを破棄
-            dbg!("recv buffer overflow");
-        }
-        self.publish_event(socket.get_sock_id(), TCPEventKind::DataArrived);
-        Ok(())
     }
 
     /// 接続を閉じる
@@ -582,111 +517,41 @@ impl TCP {
         let socket = table
             .get_mut(&sock_id)
             .context(format!("no such socket: {:?}", sock_id))?;
+        socket.connection.close(self.clock.now());
+        let status = socket.connection.status.clone();
+        self.flush(&mut table, sock_id)?;
 
-        socket.send_tcp_packet(
-            socket.send_param.next,
-            socket.recv_param.next,
-            // 注意：最初に送信するときも、ACKフラグは立てておく必要がある。ここは今までと変わらず。
-            tcpflags::FIN | tcpflags::ACK,
-            &[],
-        )?;
-
-        socket.send_param.next += 1;
-        match socket.status {
-            TcpStatus::Established => {
-                socket.status = TcpStatus::FinWait1;
-                drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
-                let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
-            }
-            TcpStatus::CloseWait => {
-                socket.status = TcpStatus::LastAck;
+        match status {
+            TcpStatus::FinWait1 | TcpStatus::LastAck => {
                 drop(table);
-                self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
+                let closed = self.wait_event(sock_id, TCPEventKind::ConnectionClosed);
                 let mut table = self.sockets.write().unwrap();
-                table.remove(&sock_id);
-                dbg!("closed & removed", sock_id);
+                let (status, error) = match table.get(&sock_id) {
+                    Some(socket) => (socket.connection.status.clone(), socket.connection.error),
+                    None => return Ok(()),
+                };
+                if status == TcpStatus::TimeWait {
+                    // TIMEWAIT の間は同じ SockID を使わせないためにソケットを残し、タイマースレッドに破棄させる
+                    return Ok(());
+                }
+                self.remove_socket(&mut table, sock_id);
+                if let (false, Some(error)) = (closed, error) {
+                    // 相手の FIN を待っている間に RST で切断された
+                    return Err(error.into());
+                }
             }
-            TcpStatus::Listen => {
-                table.remove(&sock_id);
+            // 既に RST で切断されているソケットは破棄するだけ
+            TcpStatus::Listen | TcpStatus::Closed => {
+                self.remove_socket(&mut table, sock_id);
             }
             _ => return Ok(()),
         }
 
         Ok(())
     }
-
-    /// FINWAIT1 or FINWAIT2 状態のソケットに到着したパケットの処理
-    /// これは、アクティブクローズ状態の時に受信したセグメントのハンドラになる。
-    fn finwait_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("finwait handler");
-        if socket.send_param.unacked_seq < packet.get_ack()
-            && packet.get_ack() <= socket.send_param.next
-        {
-            socket.send_param.unacked_seq = packet.get_ack();
-            self.delete_acked_segment_from_retransmission_queue(socket);
-        } else if socket.send_param.next < packet.get_ack() {
-            // 未送信セグメントに対するackは破棄
-            return Ok(());
-        }
-
-        if !packet.payload().is_empty() {
-            self.process_payload(socket, &packet)?;
-        }
-
-        if socket.status == TcpStatus::FinWait1
-            && socket.send_param.next == socket.send_param.unacked_seq
-        {
-            // 送信したFINがackされていればFinWait2へ遷移
-            socket.status = TcpStatus::FinWait2;
-            dbg!("status: finwait1 -> ", &socket.status);
-        }
-
-        if packet.get_flag() & tcpflags::FIN > 0 {
-            // 本来は CLOSING state も考慮する必要があるが省略
-            socket.recv_param.next += 1;
-            socket.send_tcp_packet(
-                socket.send_param.next,
-                socket.recv_param.next,
-                tcpflags::ACK,
-                &[],
-            )?;
-            self.publish_event(socket.get_sock_id(), TCPEventKind::ConnectionClosed);
-        }
-
-        Ok(())
-    }
-
-    fn close_handler(&self, socket: &mut Socket, packet: &TCPPacket) -> Result<()> {
-        dbg!("closewait | lastack handler");
-        socket.send_param.unacked_seq = packet.get_ack();
-        Ok(())
-    }
-}
-
-/// 宛先IPアドレスに対する送信もとインターフェースのIPアドレスを取得する。
-/// iproute2-ss180129 で動作を確認。バージョンによって挙動が変わるかも。
-fn get_source_addr_to(addr: Ipv4Addr) -> Result<Ipv4Addr> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(format!("ip route get {} | grep src", addr))
-        .output()?;
-    let mut output = str::from_utf8(&output.stdout)?
-        .trim()
-        .split_ascii_whitespace();
-    while let Some(s) = output.next() {
-        if s == "src" {
-            break;
-        }
-    }
-    let ip = output.next().context("failed to get src ip")?;
-    dbg!("source addr", ip);
-    ip.parse().context("failed to parse source ip")
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct TCPEvent {
     sock_id: SockID, // イベント発生元のソケットID
     kind: TCPEventKind,
@@ -697,11 +562,3 @@ impl TCPEvent {
         Self { sock_id, kind }
     }
 }
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum TCPEventKind {
-    ConnectionCompleted,
-    Acked,
-    DataArrived,
-    ConnectionClosed,
-}
@@ -0,0 +1,153 @@
+use crate::seqnum::SeqNum;
+
+pub const END_OF_OPTION_LIST: u8 = 0;
+pub const NO_OPERATION: u8 = 1;
+pub const MAXIMUM_SEGMENT_SIZE: u8 = 2;
+pub const WINDOW_SCALE: u8 = 3;
+pub const SACK_PERMITTED: u8 = 4;
+pub const SACK: u8 = 5;
+pub const TIMESTAMPS: u8 = 8;
+
+/// TCP ヘッダのオプションフィールドに入れられるオプションの最大バイト数 (data offset の最大 15 ワードからヘッダの 5 ワードを引いた分)
+pub const MAX_OPTIONS_SIZE: usize = 40;
+
+/// TCP オプション。
+/// 知らない種類のオプションは、長さだけ見て読み飛ばせるように Unknown として保持する。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    // SYN で、受信できるセグメントの最大のペイロードサイズを伝える (RFC 9293)
+    Mss(u16),
+    // SYN で、ウィンドウサイズを左シフトする量を伝える (RFC 7323)
+    WindowScale(u8),
+    // SYN で、SACK オプションを受け取れることを伝える (RFC 2018)
+    SackPermitted,
+    // 受信済みで、まだ ack されていないブロックの (左端, 右端) の組。右端の seq はブロックに含まない
+    Sack(Vec<(SeqNum, SeqNum)>),
+    // 送信時刻 (TSval) と、相手から最後に受け取った TSval (TSecr) (RFC 7323)
+    Timestamps { value: u32, echo_reply: u32 },
+    Unknown { kind: u8, data: Vec<u8> },
+}
+
+impl TcpOption {
+    /// kind と length を含めた、このオプションのバイト数
+    pub fn size(&self) -> usize {
+        match self {
+            TcpOption::Mss(_) => 4,
+            TcpOption::WindowScale(_) => 3,
+            TcpOption::SackPermitted => 2,
+            TcpOption::Sack(blocks) => 2 + 8 * blocks.len(),
+            TcpOption::Timestamps { .. } => 10,
+            TcpOption::Unknown { data, .. } => 2 + data.len(),
+        }
+    }
+
+    /// kind と、length に続くデータからオプションを読む。データの長さが合わない場合は Unknown にする。
+    fn read(kind: u8, data: &[u8]) -> Self {
+        match (kind, data.len()) {
+            (MAXIMUM_SEGMENT_SIZE, 2) => TcpOption::Mss(u16::from_be_bytes([data[0], data[1]])),
+            (WINDOW_SCALE, 1) => TcpOption::WindowScale(data[0]),
+            (SACK_PERMITTED, 0) => TcpOption::SackPermitted,
+            (SACK, len) if len > 0 && len.is_multiple_of(8) => TcpOption::Sack(
+                data.chunks(8)
+                    .map(|block| {
+                        (
+                            SeqNum::new(read_u32(&block[..4])),
+                            SeqNum::new(read_u32(&block[4..])),
+                        )
+                    })
+                    .collect(),
+            ),
+            (TIMESTAMPS, 8) => TcpOption::Timestamps {
+                value: read_u32(&data[..4]),
+                echo_reply: read_u32(&data[4..]),
+            },
+            _ => TcpOption::Unknown {
+                kind,
+                data: data.to_vec(),
+            },
+        }
+    }
+
+    fn write(&self, buffer: &mut Vec<u8>) {
+        match self {
+            TcpOption::Mss(mss) => {
+                buffer.extend_from_slice(&[MAXIMUM_SEGMENT_SIZE, 4]);
+                buffer.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => {
+                buffer.extend_from_slice(&[WINDOW_SCALE, 3, *shift]);
+            }
+            TcpOption::SackPermitted => buffer.extend_from_slice(&[SACK_PERMITTED, 2]),
+            TcpOption::Sack(blocks) => {
+                buffer.extend_from_slice(&[SACK, self.size() as u8]);
+                for (left, right) in blocks {
+                    buffer.extend_from_slice(&left.get().to_be_bytes());
+                    buffer.extend_from_slice(&right.get().to_be_bytes());
+                }
+            }
+            TcpOption::Timestamps { value, echo_reply } => {
+                buffer.extend_from_slice(&[TIMESTAMPS, 10]);
+                buffer.extend_from_slice(&value.to_be_bytes());
+                buffer.extend_from_slice(&echo_reply.to_be_bytes());
+            }
+            TcpOption::Unknown { kind, data } => {
+                buffer.extend_from_slice(&[*kind, self.size() as u8]);
+                buffer.extend_from_slice(data);
+            }
+        }
+    }
+}
+
+/// オプションフィールドのバイト列を先頭から順に解析するイテレータ。
+/// No-Operation は読み飛ばし、End of Option List か、長さが壊れているオプションがあればそこで終わる。
+#[derive(Debug, Clone)]
+pub struct Options<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for Options<'_> {
+    type Item = TcpOption;
+
+    fn next(&mut self) -> Option<TcpOption> {
+        loop {
+            match *self.bytes.first()? {
+                END_OF_OPTION_LIST => break,
+                NO_OPERATION => self.bytes = &self.bytes[1..],
+                kind => {
+                    let len = match self.bytes.get(1) {
+                        Some(&len) if 2 <= len as usize && len as usize <= self.bytes.len() => {
+                            len as usize
+                        }
+                        _ => break,
+                    };
+                    let data = &self.bytes[2..len];
+                    self.bytes = &self.bytes[len..];
+                    return Some(TcpOption::read(kind, data));
+                }
+            }
+        }
+        // 以降は解析しない
+        self.bytes = &[];
+        None
+    }
+}
+
+/// オプションフィールドのバイト列を解析する。
+pub fn parse(bytes: &[u8]) -> Options<'_> {
+    Options { bytes }
+}
+
+/// オプションをバイト列にする。ヘッダの長さは4バイト単位なので、末尾を End of Option List で埋める。
+pub fn build(options: &[TcpOption]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    for option in options {
+        option.write(&mut buffer);
+    }
+    let padded_len = buffer.len().div_ceil(4) * 4;
+    buffer.resize(padded_len, END_OF_OPTION_LIST);
+    buffer
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
@@ -0,0 +1,184 @@
+mod common;
+
+use common::{CLIENT_ADDR, SERVER_ADDR, SERVER_PORT};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use toytcp::clock::{Clock, ManualClock};
+use toytcp::congestion::CongestionAlgorithm;
+use toytcp::error::TCPError;
+use toytcp::loopback::LoopbackLink;
+use toytcp::simulator::{Impairment, Rule, SimulatorConfig, SimulatorLink};
+use toytcp::socket::TcpStatus;
+use toytcp::tcp::TCP;
+
+/// ManualClock を少しずつ進めながら、スレッドが終了するまで待つ。
+fn advance_until_finished<T>(clock: &ManualClock, handle: &thread::JoinHandle<T>) {
+    while !handle.is_finished() {
+        clock.advance(Duration::from_millis(100));
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[test]
+fn manual_clock_sleep_waits_for_advance() {
+    let clock = Arc::new(ManualClock::new());
+    let cloned_clock = clock.clone();
+    let sleeper = thread::spawn(move || {
+        let start = cloned_clock.now();
+        cloned_clock.sleep(Duration::from_secs(3));
+        cloned_clock.now() - start
+    });
+
+    let start = Instant::now();
+    advance_until_finished(&clock, &sleeper);
+    assert!(sleeper.join().unwrap() >= Duration::from_secs(3));
+    assert!(start.elapsed() < Duration::from_secs(3));
+}
+
+#[test]
+fn retransmits_on_virtual_clock() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client_link = SimulatorLink::new(client_link, SimulatorConfig::default());
+    // 4回続けてデータセグメントを落とすので、届くのは5回目の送信になる
+    client_link.add_rule(Rule::once(Impairment::Drop, common::is_data).times(4));
+    let clock = Arc::new(ManualClock::new());
+    let client = TCP::with_clock(client_link.clone(), clock.clone());
+    let server_thread = common::spawn_file_server(TCP::with_clock(server_link, clock.clone()));
+
+    let input = common::test_data(1000);
+    let cloned_input = input.clone();
+    let client_thread = thread::spawn(move || common::send_file(&client, &cloned_input));
+
+    let start = Instant::now();
+    advance_until_finished(&clock, &server_thread);
+    assert_eq!(server_thread.join().unwrap(), input);
+    client_thread.join().unwrap();
+
+    assert_eq!(client_link.history().len(), 4);
+    // 再送タイムアウト 1 秒から倍々に 1 + 2 + 4 + 8 秒分の時間が仮想的に経過している
+    assert!(clock.now() >= Duration::from_secs(15));
+    assert!(start.elapsed() < Duration::from_secs(10));
+}
+
+#[test]
+fn reaps_time_wait_socket_on_virtual_clock() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let clock = Arc::new(ManualClock::new());
+    let client = TCP::with_clock(client_link, clock.clone());
+    client.set_msl(Duration::from_secs(1));
+    let server_thread = common::spawn_file_server(TCP::with_clock(server_link, clock.clone()));
+
+    let input = common::test_data(1000);
+    let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+    client.send(sock_id, &input).unwrap();
+    client.close(sock_id).unwrap();
+    let start = clock.now();
+    // close が戻った後も、2*MSL の間は同じ SockID を使わせないためにソケットが残っている
+    assert_eq!(client.status(sock_id).unwrap(), TcpStatus::TimeWait);
+    advance_until_finished(&clock, &server_thread);
+    assert_eq!(server_thread.join().unwrap(), input);
+
+    while client.status(sock_id).is_ok() {
+        clock.advance(Duration::from_millis(100));
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(clock.now() - start >= Duration::from_secs(2));
+}
+
+#[test]
+fn times_out_unreachable_connection_on_virtual_clock() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client_link = SimulatorLink::new(client_link, SimulatorConfig::default());
+    let clock = Arc::new(ManualClock::new());
+    let client = TCP::with_clock(client_link.clone(), clock.clone());
+    client.set_user_timeout(Duration::from_secs(10));
+    let server = TCP::with_clock(server_link, clock.clone());
+    let listening_socket = server.listen(SERVER_ADDR, SERVER_PORT).unwrap();
+
+    let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+    server.accept(listening_socket).unwrap();
+    // 接続後は相手に何も届かなくなる
+    client_link.add_rule(Rule::once(Impairment::Drop, |_| true).times(usize::MAX));
+    client.send(sock_id, b"hello").unwrap();
+    let start = clock.now();
+    let cloned_client = client.clone();
+    let receiver = thread::spawn(move || cloned_client.recv(sock_id, &mut [0; 16]));
+
+    advance_until_finished(&clock, &receiver);
+    let error = receiver.join().unwrap().unwrap_err();
+    assert_eq!(error.downcast_ref(), Some(&TCPError::TimedOut));
+    assert!(clock.now() - start >= Duration::from_secs(10));
+    let error = client.send(sock_id, b"world").unwrap_err();
+    assert_eq!(error.downcast_ref(), Some(&TCPError::TimedOut));
+    client.close(sock_id).unwrap();
+}
+
+#[test]
+fn connect_times_out_on_virtual_clock() {
+    let (client_link, _server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let clock = Arc::new(ManualClock::new());
+    let client = TCP::with_clock(client_link, clock.clone());
+    client.set_user_timeout(Duration::from_secs(5));
+
+    // 相手側に TCP がいないので、SYN に誰も応答しない
+    let cloned_client = client.clone();
+    let connector = thread::spawn(move || cloned_client.connect(SERVER_ADDR, SERVER_PORT));
+    advance_until_finished(&clock, &connector);
+    let error = connector.join().unwrap().unwrap_err();
+    assert_eq!(error.downcast_ref(), Some(&TCPError::TimedOut));
+}
+
+#[test]
+fn recovers_from_loss_without_timeout() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client_link = SimulatorLink::new(client_link, SimulatorConfig::default());
+    client_link.add_rule(Rule::once(Impairment::Drop, common::is_data));
+    // 時計を進めないので、再送タイムアウトは起こらない
+    let clock = Arc::new(ManualClock::new());
+    let client = TCP::with_clock(client_link.clone(), clock.clone());
+    let server_thread = common::spawn_file_server(TCP::with_clock(server_link, clock.clone()));
+
+    let input = common::test_data(20_000);
+    let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+    client.send(sock_id, &input).unwrap();
+    client.close(sock_id).unwrap();
+    assert_eq!(server_thread.join().unwrap(), input);
+    assert_eq!(client_link.history().len(), 1);
+    let stats = client.stats(sock_id).unwrap();
+    assert_eq!(stats.retransmissions, 0);
+    assert!(stats.fast_retransmissions >= 1);
+    assert!(stats.ssthresh < usize::MAX);
+}
+
+#[test]
+fn paces_segments_on_virtual_clock() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let clock = Arc::new(ManualClock::new());
+    let client = TCP::with_clock(client_link, clock.clone());
+    let server = TCP::with_clock(server_link, clock.clone());
+    let listening_socket = server.listen(SERVER_ADDR, SERVER_PORT).unwrap();
+    let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+    client
+        .set_congestion_control(sock_id, CongestionAlgorithm::Bbr)
+        .unwrap();
+    let connected_socket = server.accept(listening_socket).unwrap();
+
+    let input = common::test_data(4380);
+    let cloned_input = input.clone();
+    let sender = thread::spawn(move || client.send(sock_id, &cloned_input).unwrap());
+    // 最初のセグメントだけが送られ、時計が進むまで次のセグメントは送られない
+    // ペイロードは、MSS からタイムスタンプオプションの 12 バイトを引いた分
+    let mut buffer = vec![0; 4380];
+    assert_eq!(server.recv(connected_socket, &mut buffer).unwrap(), 1448);
+    thread::sleep(Duration::from_millis(50));
+    assert!(!sender.is_finished());
+
+    advance_until_finished(&clock, &sender);
+    let mut received = buffer[..1448].to_vec();
+    while received.len() < input.len() {
+        let n = server.recv(connected_socket, &mut buffer).unwrap();
+        received.extend_from_slice(&buffer[..n]);
+    }
+    assert_eq!(received, input);
+}
@@ -0,0 +1,113 @@
+#![allow(dead_code)]
+
+use pnet::packet::Packet;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use toytcp::connection::{AcceptOutcome, Connection};
+use toytcp::packet::TCPPacket;
+use toytcp::seqnum::SeqNum;
+use toytcp::socket::TcpStatus;
+use toytcp::tcp::TCP;
+
+pub const CLIENT_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+pub const SERVER_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+pub const SERVER_PORT: u16 = 40000;
+pub const CLIENT_PORT: u16 = 50000;
+
+/// examples/fileserver.rs と同じ流れで1つの接続を受け付け、受信したデータを全て返すスレッドを起動する。
+pub fn spawn_file_server(server: Arc<TCP>) -> JoinHandle<Vec<u8>> {
+    let listening_socket = server.listen(SERVER_ADDR, SERVER_PORT).unwrap();
+    thread::spawn(move || {
+        let connected_socket = server.accept(listening_socket).unwrap();
+        let mut v = Vec::new();
+        let mut buffer = [0u8; 2000];
+        loop {
+            let nbytes = server.recv(connected_socket, &mut buffer).unwrap();
+            if nbytes == 0 {
+                server.close(connected_socket).unwrap();
+                return v;
+            }
+            v.extend_from_slice(&buffer[..nbytes]);
+        }
+    })
+}
+
+/// examples/fileclient.rs と同じ流れでデータを送信して接続を閉じる。
+pub fn send_file(client: &TCP, input: &[u8]) {
+    let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+    client.send(sock_id, input).unwrap();
+    client.close(sock_id).unwrap();
+}
+
+pub fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// ペイロードを持つセグメントかどうか。シミュレータのルールで使う。
+pub fn is_data(packet: &TCPPacket) -> bool {
+    !packet.payload().is_empty()
+}
+
+/// from が送信待ちにしているセグメントを全て to に渡し、渡したセグメントを返す。
+pub fn deliver(from: &mut Connection, to: &mut Connection, now: Duration) -> Vec<TCPPacket> {
+    let mut delivered = Vec::new();
+    while let Some(packet) = from.poll_transmit() {
+        to.handle_segment(&packet, now);
+        delivered.push(packet);
+    }
+    delivered
+}
+
+/// 2つのコネクションの間で、送信待ちのセグメントがなくなるまでやり取りさせる。
+pub fn exchange(a: &mut Connection, b: &mut Connection, now: Duration) {
+    while !deliver(a, b, now).is_empty() | !deliver(b, a, now).is_empty() {}
+}
+
+/// リスニング状態のコネクションを生成する。
+pub fn listener() -> Connection {
+    Connection::new(
+        SERVER_ADDR,
+        Ipv4Addr::UNSPECIFIED,
+        SERVER_PORT,
+        0,
+        TcpStatus::Listen,
+    )
+}
+
+/// スレッドを使わずに3ウェイハンドシェイクを行い、(client, server) を返す。
+pub fn establish(now: Duration) -> (Connection, Connection) {
+    establish_at(SeqNum::new(1000), SeqNum::new(5000), now)
+}
+
+/// タイムスタンプオプションを使わずに establish する。データセグメントのペイロードを MSS いっぱいにできる。
+pub fn establish_without_timestamps(now: Duration) -> (Connection, Connection) {
+    let (mut client, mut server) = establish(now);
+    client.timestamps = false;
+    server.timestamps = false;
+    (client, server)
+}
+
+/// 初期シーケンス番号を指定して establish する。
+pub fn establish_at(
+    client_iss: SeqNum,
+    server_iss: SeqNum,
+    now: Duration,
+) -> (Connection, Connection) {
+    let mut client = Connection::connect(
+        CLIENT_ADDR,
+        SERVER_ADDR,
+        CLIENT_PORT,
+        SERVER_PORT,
+        client_iss,
+        now,
+    );
+    let syn = client.poll_transmit().unwrap();
+    let mut server = match listener().accept(CLIENT_ADDR, &syn, server_iss, now) {
+        AcceptOutcome::Accepted(server) => *server,
+        _ => panic!("SYN was not accepted"),
+    };
+    exchange(&mut client, &mut server, now);
+    (client, server)
+}
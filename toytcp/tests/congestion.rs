@@ -0,0 +1,359 @@
+use std::cmp;
+use std::time::Duration;
+use toytcp::congestion::{Bbr, BbrMode, CongestionAlgorithm, CongestionControl, Cubic, Reno};
+
+const MSS: usize = 1460;
+
+#[test]
+fn reno_starts_with_initial_window() {
+    let reno = CongestionAlgorithm::Reno.build(MSS);
+    assert_eq!(reno.cwnd(), 3 * MSS);
+    assert_eq!(reno.ssthresh(), usize::MAX);
+    assert_eq!(reno.pacing_rate(), None);
+}
+
+#[test]
+fn reno_slow_start_grows_by_acked_bytes() {
+    let mut reno = Reno::new(MSS);
+    reno.on_ack(MSS, None, Duration::ZERO);
+    assert_eq!(reno.cwnd(), 4 * MSS);
+    // 1つの ACK で増やすのは1セグメント分まで
+    reno.on_ack(3 * MSS, None, Duration::ZERO);
+    assert_eq!(reno.cwnd(), 5 * MSS);
+}
+
+#[test]
+fn reno_halves_window_on_loss() {
+    let mut reno = Reno::new(MSS);
+    reno.on_loss(10 * MSS, Duration::ZERO);
+    assert_eq!(reno.ssthresh(), 5 * MSS);
+    assert_eq!(reno.cwnd(), 5 * MSS);
+
+    // 輻輳回避: cwnd 分が ack されるごとに1セグメント増やす
+    for _ in 0..4 {
+        reno.on_ack(MSS, None, Duration::ZERO);
+    }
+    assert_eq!(reno.cwnd(), 5 * MSS);
+    reno.on_ack(MSS, None, Duration::ZERO);
+    assert_eq!(reno.cwnd(), 6 * MSS);
+
+    // 送信中のデータが少なくても、ssthresh は2セグメントより小さくしない
+    reno.on_loss(MSS, Duration::ZERO);
+    assert_eq!(reno.ssthresh(), 2 * MSS);
+}
+
+#[test]
+fn reno_inflates_window_during_fast_recovery() {
+    let mut reno = Reno::new(MSS);
+    reno.on_loss(10 * MSS, Duration::ZERO);
+    for _ in 0..3 {
+        reno.on_duplicate_ack(Duration::ZERO);
+    }
+    assert_eq!(reno.cwnd(), 8 * MSS);
+    reno.on_duplicate_ack(Duration::ZERO);
+    assert_eq!(reno.cwnd(), 9 * MSS);
+
+    // partial ACK では ack された分だけ縮め、1セグメント分は足し戻す
+    reno.on_partial_ack(2 * MSS, Duration::ZERO);
+    assert_eq!(reno.cwnd(), 8 * MSS);
+
+    reno.on_recovery_end(Duration::ZERO);
+    assert_eq!(reno.cwnd(), 5 * MSS);
+    assert_eq!(reno.ssthresh(), 5 * MSS);
+}
+
+#[test]
+fn reno_restarts_slow_start_on_rto() {
+    let mut reno = Reno::new(MSS);
+    reno.on_rto(8 * MSS, Duration::ZERO);
+    assert_eq!(reno.cwnd(), MSS);
+    assert_eq!(reno.ssthresh(), 4 * MSS);
+    reno.on_ack(MSS, None, Duration::ZERO);
+    assert_eq!(reno.cwnd(), 2 * MSS);
+}
+
+/// cwnd をセグメント数で返す。
+fn segments(cc: &dyn CongestionControl) -> f64 {
+    cc.cwnd() as f64 / MSS as f64
+}
+
+/// cwnd 分のセグメントを1つずつ ack して、時刻を1 RTT 進める。
+fn ack_round(cc: &mut dyn CongestionControl, rtt: Duration, now: &mut Duration) {
+    for _ in 0..cc.cwnd() / MSS {
+        cc.on_ack(MSS, Some(rtt), *now);
+    }
+    *now += rtt;
+}
+
+/// スロースタートで cwnd を count セグメントまで増やしてから、ロスを検出させる。
+fn cubic_after_loss_at(count: usize) -> Cubic {
+    let mut cubic = Cubic::new(MSS);
+    while cubic.cwnd() < count * MSS {
+        cubic.on_ack(MSS, Some(Duration::from_millis(100)), Duration::ZERO);
+    }
+    cubic.on_loss(count * MSS, Duration::ZERO);
+    cubic
+}
+
+#[test]
+fn cubic_reduces_window_by_beta_on_loss() {
+    let cubic = cubic_after_loss_at(100);
+    assert!((segments(&cubic) - 70.0).abs() < 0.01);
+    assert_eq!(cubic.ssthresh(), cubic.cwnd());
+}
+
+#[test]
+fn cubic_window_follows_cubic_curve_around_w_max() {
+    let mut cubic = cubic_after_loss_at(100);
+    let rtt = Duration::from_millis(100);
+    let mut now = Duration::ZERO;
+    let mut curve = vec![segments(&cubic)];
+    for _ in 0..80 {
+        ack_round(&mut cubic, rtt, &mut now);
+        curve.push(segments(&cubic));
+    }
+    let growth = |round: usize| curve[round + 1] - curve[round];
+
+    // W_max の手前では、近づくほど増え方が緩やかになる (concave)
+    assert!(growth(1) > 2.0);
+    assert!(growth(1) > growth(10));
+    assert!(growth(10) > growth(30));
+    // K = cbrt((100 - 70) / 0.4) ≈ 4.2 秒のあたりで W_max に留まる
+    for &cwnd in &curve[38..48] {
+        assert!((cwnd - 100.0).abs() < 0.5, "{:?}", curve);
+    }
+    // W_max を超えると、離れるほど増え方が急になる (convex)
+    assert!(growth(55) > growth(48));
+    assert!(curve[80] > 110.0);
+    // 1 RTT で 1.5 倍より大きくは増やさない
+    assert!(curve.windows(2).all(|w| w[1] <= w[0] * 1.5));
+}
+
+#[test]
+fn cubic_follows_reno_when_rtt_is_short() {
+    let mut cubic = cubic_after_loss_at(100);
+    let rtt = Duration::from_millis(1);
+    let mut now = Duration::ZERO;
+    for _ in 0..100 {
+        ack_round(&mut cubic, rtt, &mut now);
+    }
+    // 0.1 秒では3次関数はほとんど増えないが、Reno 相当のウィンドウは 1 RTT ごとに増える
+    assert!(segments(&cubic) > 120.0);
+}
+
+#[test]
+fn cubic_converges_faster_after_repeated_loss() {
+    let mut repeated = cubic_after_loss_at(100);
+    // W_max に戻る前にもう一度ロスしたので、W_max を 70 ではなく 70 * (1 + beta) / 2 に下げる
+    repeated.on_loss(70 * MSS, Duration::ZERO);
+    assert!((segments(&repeated) - 49.0).abs() < 0.01);
+    // 同じ 70 セグメントで初めてロスした場合と比べる
+    let mut first = cubic_after_loss_at(70);
+    assert!((segments(&first) - 49.0).abs() < 0.01);
+
+    let rtt = Duration::from_millis(100);
+    let (mut now, mut first_now) = (Duration::ZERO, Duration::ZERO);
+    for _ in 0..20 {
+        ack_round(&mut repeated, rtt, &mut now);
+        ack_round(&mut first, rtt, &mut first_now);
+    }
+    assert!(segments(&repeated) < 61.0);
+    assert!(segments(&first) > 66.0);
+}
+
+#[test]
+fn cubic_restarts_slow_start_on_rto() {
+    let mut cubic = cubic_after_loss_at(100);
+    cubic.on_rto(70 * MSS, Duration::ZERO);
+    assert_eq!(cubic.cwnd(), MSS);
+    assert!((cubic.ssthresh() as f64 / MSS as f64 - 49.0).abs() < 0.01);
+    cubic.on_ack(MSS, None, Duration::ZERO);
+    assert_eq!(cubic.cwnd(), 2 * MSS);
+}
+
+#[test]
+fn hystart_exits_slow_start_when_rtt_increases() {
+    let mut cubic = Cubic::new(MSS);
+    let mut now = Duration::ZERO;
+    for _ in 0..3 {
+        ack_round(&mut cubic, Duration::from_millis(100), &mut now);
+    }
+    assert_eq!(segments(&cubic), 24.0);
+
+    // RTT が 100ms / 8 以上増えたので、Conservative Slow Start に移って増え方を 1/4 にする
+    ack_round(&mut cubic, Duration::from_millis(120), &mut now);
+    assert!(segments(&cubic) < 40.0);
+    assert_eq!(cubic.ssthresh(), usize::MAX);
+
+    // CSS が 5 ラウンド続いたら、ロスを待たずにスロースタートを終える
+    let mut rounds = 0;
+    while cubic.ssthresh() == usize::MAX {
+        ack_round(&mut cubic, Duration::from_millis(120), &mut now);
+        rounds += 1;
+    }
+    assert!(rounds <= 6);
+    assert!(cubic.ssthresh() <= cubic.cwnd());
+    // スロースタートを続けていれば 24 * 2^6 セグメントになっていた
+    assert!(segments(&cubic) < 200.0);
+}
+
+#[test]
+fn hystart_resumes_slow_start_when_rtt_decreases() {
+    let mut cubic = Cubic::new(MSS);
+    let mut now = Duration::ZERO;
+    for _ in 0..3 {
+        ack_round(&mut cubic, Duration::from_millis(100), &mut now);
+    }
+    ack_round(&mut cubic, Duration::from_millis(120), &mut now);
+    let before = cubic.cwnd();
+    cubic.on_ack(MSS, Some(Duration::from_millis(120)), now);
+    assert_eq!(cubic.cwnd() - before, MSS / 4);
+
+    // RTT の増加は一時的なものだった
+    ack_round(&mut cubic, Duration::from_millis(100), &mut now);
+    let before = cubic.cwnd();
+    cubic.on_ack(MSS, Some(Duration::from_millis(100)), now);
+    assert_eq!(cubic.cwnd() - before, MSS);
+}
+
+// 帯域 1000 セグメント/秒、往復遅延 100ms のボトルネック。BDP は 100 セグメント
+const BOTTLENECK_BW: f64 = 1000.0;
+const BASE_RTT: Duration = Duration::from_millis(100);
+
+/// 送信側が常に cwnd 分のセグメントを送っているとして、ボトルネックを通って届く ACK を until まで渡す。
+/// BDP を超えて送った分はボトルネックのキューに溜まり、その分 RTT が伸びる。最後の ACK の RTT を返す。
+fn run_bottleneck(cc: &mut dyn CongestionControl, now: &mut Duration, until: Duration) -> Duration {
+    let mut rtt = BASE_RTT;
+    while *now < until {
+        let cwnd = cmp::max(cc.cwnd() / MSS, 1) as f64;
+        let queue = (cwnd - BOTTLENECK_BW * BASE_RTT.as_secs_f64()).max(0.0);
+        rtt = BASE_RTT + Duration::from_secs_f64(queue / BOTTLENECK_BW);
+        // cwnd が BDP より小さければ1 RTT に cwnd 個、そうでなければボトルネックの帯域で ACK が届く
+        let interval = (1.0 / BOTTLENECK_BW).max(rtt.as_secs_f64() / cwnd);
+        *now += Duration::from_secs_f64(interval);
+        cc.on_ack(MSS, Some(rtt), *now);
+    }
+    rtt
+}
+
+#[test]
+fn bbr_starts_with_high_gain_pacing() {
+    let bbr = Bbr::new(MSS);
+    assert_eq!(bbr.mode(), BbrMode::Startup);
+    assert_eq!(bbr.cwnd(), 3 * MSS);
+    assert_eq!(bbr.ssthresh(), usize::MAX);
+    // 初期ウィンドウを 1ms で送れる速さの 2/ln(2) 倍
+    assert_eq!(bbr.pacing_rate(), Some((2.885 * 4380.0 / 0.001) as u64));
+}
+
+#[test]
+fn bbr_estimates_bottleneck_bandwidth_and_min_rtt() {
+    let mut bbr = Bbr::new(MSS);
+    let mut now = Duration::ZERO;
+    let rtt = run_bottleneck(&mut bbr, &mut now, Duration::from_secs(5));
+    assert_eq!(bbr.mode(), BbrMode::ProbeBw);
+    let bw = bbr.btl_bw().unwrap() / MSS as f64;
+    assert!((bw - BOTTLENECK_BW).abs() < BOTTLENECK_BW * 0.05, "{}", bw);
+    assert_eq!(bbr.min_rtt(), Some(BASE_RTT));
+    // cwnd は BDP の2倍に保たれるので、キューは際限なく伸びない
+    let cwnd = segments(&bbr);
+    assert!((190.0..=210.0).contains(&cwnd), "{}", cwnd);
+    assert!(rtt <= BASE_RTT * 21 / 10);
+
+    // ロスが起きない限り cwnd を増やし続ける CUBIC では、キューが溜まり続けて RTT が伸びる
+    let mut cubic = Cubic::new(MSS);
+    let mut now = Duration::ZERO;
+    assert!(run_bottleneck(&mut cubic, &mut now, Duration::from_secs(5)) > BASE_RTT * 5);
+}
+
+#[test]
+fn bbr_cycles_pacing_gain_in_probe_bw() {
+    let mut bbr = Bbr::new(MSS);
+    let mut now = Duration::ZERO;
+    run_bottleneck(&mut bbr, &mut now, Duration::from_secs(5));
+    let bw = bbr.btl_bw().unwrap();
+    let mut gains = Vec::new();
+    // 各位相は少なくとも1ラウンド (cwnd 分が ack されるまで) 続くので、一周するのに数秒かかる
+    for i in 1..=300 {
+        run_bottleneck(
+            &mut bbr,
+            &mut now,
+            Duration::from_secs(5) + BASE_RTT * i / 10,
+        );
+        let gain = (bbr.pacing_rate().unwrap() as f64 / bw * 100.0).round() / 100.0;
+        if gains.last() != Some(&gain) {
+            gains.push(gain);
+        }
+    }
+    // 帯域が増えていないか 1.25 倍で探り、溜まったキューを 0.75 倍で解消してから、しばらく推定値のまま送る
+    assert!(
+        gains.windows(3).any(|w| w == [1.25, 0.75, 1.0]),
+        "{:?}",
+        gains
+    );
+}
+
+#[test]
+fn bbr_keeps_each_gain_phase_for_a_round_on_short_rtt() {
+    let mut bbr = Bbr::new(MSS);
+    let mut now = Duration::ZERO;
+    run_bottleneck(&mut bbr, &mut now, Duration::from_secs(5));
+    let bw = bbr.btl_bw().unwrap();
+    // 最小 RTT が ACK の間隔より短くなっても、位相は ACK ごとには変わらない
+    let mut gains: Vec<(f64, usize)> = Vec::new();
+    for _ in 0..200 {
+        now += Duration::from_millis(1);
+        bbr.on_ack(MSS, Some(Duration::from_micros(10)), now);
+        let gain = (bbr.pacing_rate().unwrap() as f64 / bw * 100.0).round() / 100.0;
+        match gains.last_mut() {
+            Some((last, count)) if *last == gain => *count += 1,
+            _ => gains.push((gain, 1)),
+        }
+    }
+    assert!(gains.len() > 2, "{:?}", gains);
+    // 1ラウンドは少なくとも最小の cwnd の4セグメント分の ACK が届くまで続く
+    assert!(
+        gains[1..gains.len() - 1]
+            .iter()
+            .all(|&(_, count)| count >= 4),
+        "{:?}",
+        gains
+    );
+}
+
+#[test]
+fn bbr_probes_min_rtt_periodically() {
+    let mut bbr = Bbr::new(MSS);
+    let mut now = Duration::ZERO;
+    run_bottleneck(&mut bbr, &mut now, Duration::from_secs(5));
+    // 最小 RTT はスタートアップの最初に計測したきり更新されないので、10 秒経つと ProbeRTT に入る
+    let mut probed = false;
+    while now < Duration::from_secs(11) {
+        let until = now + Duration::from_millis(10);
+        run_bottleneck(&mut bbr, &mut now, until);
+        if bbr.mode() == BbrMode::ProbeRtt {
+            probed = true;
+            assert_eq!(bbr.cwnd(), 4 * MSS);
+        }
+    }
+    assert!(probed);
+    run_bottleneck(&mut bbr, &mut now, Duration::from_secs(12));
+    assert_eq!(bbr.mode(), BbrMode::ProbeBw);
+    assert_eq!(bbr.min_rtt(), Some(BASE_RTT));
+}
+
+#[test]
+fn bbr_keeps_model_on_loss() {
+    let mut bbr = Bbr::new(MSS);
+    let mut now = Duration::ZERO;
+    run_bottleneck(&mut bbr, &mut now, Duration::from_secs(5));
+    let cwnd = bbr.cwnd();
+    bbr.on_loss(cwnd, now);
+    assert_eq!(bbr.cwnd(), cwnd);
+
+    bbr.on_rto(cwnd, now);
+    assert_eq!(bbr.cwnd(), MSS);
+    // 推定した帯域と RTT は残っているので、ack されるにつれて元の cwnd に戻る
+    run_bottleneck(&mut bbr, &mut now, Duration::from_secs(7));
+    assert!(segments(&bbr) >= 190.0);
+}
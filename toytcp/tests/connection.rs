@@ -0,0 +1,1432 @@
+mod common;
+
+use common::{
+    establish, establish_at, establish_without_timestamps, exchange, CLIENT_ADDR, CLIENT_PORT,
+    SERVER_ADDR, SERVER_PORT,
+};
+use pnet::packet::Packet;
+use std::time::Duration;
+use toytcp::congestion::{CongestionAlgorithm, CongestionControl};
+use toytcp::connection::{reset_segment, AcceptOutcome, Connection, TCPEventKind};
+use toytcp::error::TCPError;
+use toytcp::packet::TCPPacket;
+use toytcp::seqnum::SeqNum;
+use toytcp::socket::TcpStatus;
+use toytcp::tcpflags;
+use toytcp::tcpoption::TcpOption;
+
+/// コネクションに溜まっているイベントを全て取り出す。
+fn events(connection: &mut Connection) -> Vec<TCPEventKind> {
+    std::iter::from_fn(|| connection.poll_event()).collect()
+}
+
+#[test]
+fn three_way_handshake() {
+    let (mut client, mut server) = establish(Duration::ZERO);
+    assert_eq!(client.status, TcpStatus::Established);
+    assert_eq!(server.status, TcpStatus::Established);
+    assert!(events(&mut client).contains(&TCPEventKind::ConnectionCompleted));
+    assert!(events(&mut server).contains(&TCPEventKind::ConnectionCompleted));
+    assert_eq!(client.send_param.next, server.recv_param.next);
+    assert_eq!(server.send_param.next, client.recv_param.next);
+}
+
+/// まだ接続していないクライアントの SYN を生成する。
+fn client_syn() -> (Connection, TCPPacket) {
+    let mut client = Connection::connect(
+        CLIENT_ADDR,
+        SERVER_ADDR,
+        CLIENT_PORT,
+        SERVER_PORT,
+        SeqNum::new(1000),
+        Duration::ZERO,
+    );
+    let syn = client.poll_transmit().unwrap();
+    (client, syn)
+}
+
+#[test]
+fn accepted_connection_inherits_congestion_control() {
+    let (_, syn) = client_syn();
+    let mut listener = common::listener();
+    listener.set_congestion_control(CongestionAlgorithm::Cubic);
+    match listener.accept(CLIENT_ADDR, &syn, SeqNum::new(5000), Duration::ZERO) {
+        AcceptOutcome::Accepted(server) => {
+            assert_eq!(server.congestion_algorithm, CongestionAlgorithm::Cubic);
+        }
+        _ => panic!("SYN was not accepted"),
+    }
+}
+
+#[test]
+fn listener_resets_ack() {
+    let (_, mut segment) = client_syn();
+    segment.set_flag(tcpflags::ACK);
+    segment.set_ack(SeqNum::new(12345));
+    match common::listener().accept(CLIENT_ADDR, &segment, SeqNum::new(5000), Duration::ZERO) {
+        AcceptOutcome::Reset(rst) => {
+            assert_eq!(rst.get_flag(), tcpflags::RST);
+            assert_eq!(rst.get_seq(), SeqNum::new(12345));
+            assert_eq!(rst.get_dest(), CLIENT_PORT);
+            assert!(rst.is_correct_checksum(SERVER_ADDR, CLIENT_ADDR));
+        }
+        _ => panic!("ACK to a listening socket must be reset"),
+    }
+}
+
+#[test]
+fn listener_discards_rst_and_segment_without_syn() {
+    let (_, mut segment) = client_syn();
+    for flag in [tcpflags::RST, tcpflags::RST | tcpflags::ACK, tcpflags::FIN] {
+        segment.set_flag(flag);
+        assert!(matches!(
+            common::listener().accept(CLIENT_ADDR, &segment, SeqNum::new(5000), Duration::ZERO),
+            AcceptOutcome::Discarded
+        ));
+    }
+}
+
+#[test]
+fn resets_segment_to_closed_port() {
+    let (_, syn) = client_syn();
+    // ACK が立っていないので、SYN の分を含めた長さを ack する
+    let rst = reset_segment(SERVER_ADDR, CLIENT_ADDR, &syn).unwrap();
+    assert_eq!(rst.get_flag(), tcpflags::RST | tcpflags::ACK);
+    assert_eq!(rst.get_seq(), SeqNum::new(0));
+    assert_eq!(rst.get_ack(), syn.get_seq() + 1);
+    assert_eq!((rst.get_src(), rst.get_dest()), (SERVER_PORT, CLIENT_PORT));
+    assert!(rst.is_correct_checksum(SERVER_ADDR, CLIENT_ADDR));
+
+    // RST には RST を返さない
+    assert!(reset_segment(CLIENT_ADDR, SERVER_ADDR, &rst).is_none());
+}
+
+#[test]
+fn synsent_resets_unacceptable_ack() {
+    let (mut client, syn) = client_syn();
+    let mut server =
+        match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), Duration::ZERO) {
+            AcceptOutcome::Accepted(server) => *server,
+            _ => unreachable!(),
+        };
+    let mut syn_ack = server.poll_transmit().unwrap();
+    // 送信した SYN を ack していない SYN|ACK
+    syn_ack.set_ack(syn.get_seq());
+    client.handle_segment(&syn_ack, Duration::ZERO);
+    assert_eq!(client.status, TcpStatus::SynSent);
+    let rst = client.poll_transmit().unwrap();
+    assert_eq!(rst.get_flag(), tcpflags::RST);
+    assert_eq!(rst.get_seq(), syn.get_seq());
+    assert!(client.poll_event().is_none());
+}
+
+#[test]
+fn synrcvd_resets_unacceptable_ack() {
+    let (_, syn) = client_syn();
+    let mut server =
+        match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), Duration::ZERO) {
+            AcceptOutcome::Accepted(server) => *server,
+            _ => unreachable!(),
+        };
+    let syn_ack = server.poll_transmit().unwrap();
+    let mut ack = syn.clone();
+    ack.set_flag(tcpflags::ACK);
+    ack.set_seq(syn.get_seq() + 1);
+    ack.set_ack(syn_ack.get_seq() + 100);
+    server.handle_segment(&ack, Duration::ZERO);
+    assert_eq!(server.status, TcpStatus::SynRcvd);
+    let rst = server.poll_transmit().unwrap();
+    assert_eq!(rst.get_flag(), tcpflags::RST);
+    assert_eq!(rst.get_seq(), syn_ack.get_seq() + 100);
+}
+
+#[test]
+fn synrcvd_drops_segment_outside_receive_window() {
+    let (_, syn) = client_syn();
+    let mut server =
+        match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), Duration::ZERO) {
+            AcceptOutcome::Accepted(server) => *server,
+            _ => unreachable!(),
+        };
+    let syn_ack = server.poll_transmit().unwrap();
+    let mut ack = syn.clone();
+    ack.set_flag(tcpflags::ACK);
+    ack.set_seq(syn.get_seq() + 10_000);
+    ack.set_ack(syn_ack.get_seq() + 1);
+    server.handle_segment(&ack, Duration::ZERO);
+    assert_eq!(server.status, TcpStatus::SynRcvd);
+    assert_eq!(server.stats.dropped_out_of_window, 1);
+    // 次に受信したい seq を ACK で伝え直す
+    let reply = server.poll_transmit().unwrap();
+    assert_eq!(reply.get_flag(), tcpflags::ACK);
+    assert_eq!(reply.get_ack(), syn.get_seq() + 1);
+}
+
+#[test]
+fn transfers_data() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let input = common::test_data(10_000);
+    let mut output = Vec::new();
+    let mut cursor = 0;
+    let mut buffer = [0; 2000];
+    while output.len() < input.len() {
+        cursor += client.send(&input[cursor..], now).unwrap();
+        exchange(&mut client, &mut server, now);
+        while let Some(n) = server.recv(&mut buffer, now).unwrap() {
+            output.extend_from_slice(&buffer[..n]);
+        }
+        assert!(client.retransmission_queue.is_empty());
+    }
+    // 読み込んで空いた server のウィンドウは、server からの ACK で伝わる
+    assert_eq!(client.stats.window_probes, 0);
+    assert_eq!(output, input);
+    assert!(events(&mut server).contains(&TCPEventKind::DataArrived));
+    assert!(events(&mut client).contains(&TCPEventKind::Acked));
+}
+
+#[test]
+fn transfers_data_across_wrap_point() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish_at(
+        SeqNum::new(u32::MAX - 100),
+        SeqNum::new(u32::MAX - 3000),
+        now,
+    );
+    let input = common::test_data(10_000);
+    let mut output = Vec::new();
+    let mut cursor = 0;
+    let mut buffer = [0; 2000];
+    while output.len() < input.len() {
+        cursor += client.send(&input[cursor..], now).unwrap();
+        exchange(&mut client, &mut server, now);
+        while let Some(n) = server.recv(&mut buffer, now).unwrap() {
+            output.extend_from_slice(&buffer[..n]);
+        }
+    }
+    assert_eq!(output, input);
+    assert!(client.retransmission_queue.is_empty());
+    // ヘッダ上の値は一周して小さくなっている
+    assert!(client.send_param.next.get() < 10_000);
+
+    // 逆方向も同様に一周をまたぐ
+    assert_eq!(server.send(&input[..4000], now), Ok(4000));
+    exchange(&mut client, &mut server, now);
+    let mut output = Vec::new();
+    while let Some(n) = client.recv(&mut buffer, now).unwrap() {
+        output.extend_from_slice(&buffer[..n]);
+    }
+    assert_eq!(output, &input[..4000]);
+
+    client.close(now);
+    exchange(&mut client, &mut server, now);
+    server.close(now);
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.status, TcpStatus::TimeWait);
+    assert_eq!(server.status, TcpStatus::Closed);
+}
+
+#[test]
+fn ignores_already_received_prefix() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish_at(SeqNum::new(u32::MAX - 2), SeqNum::new(5000), now);
+    assert_eq!(client.send(b"hello", now), Ok(5));
+    let first = client.poll_transmit().unwrap();
+    server.handle_segment(&first, now);
+    server.poll_transmit().unwrap();
+    assert_eq!(client.send(b" world", now), Ok(6));
+    let second = client.poll_transmit().unwrap();
+
+    // 1つ目と2つ目をまとめた再送セグメントが届いたことにする
+    server.handle_segment(&data_from(&client, first.get_seq(), b"hello world"), now);
+    let ack = server.poll_transmit().unwrap();
+    assert_eq!(ack.get_ack(), second.get_seq() + 6);
+    // 全て受信済みのセグメントには ACK を返すだけ
+    server.handle_segment(&second, now);
+    assert_eq!(server.poll_transmit().unwrap().get_ack(), ack.get_ack());
+
+    let mut buffer = [0; 16];
+    assert_eq!(server.recv(&mut buffer, now), Ok(Some(11)));
+    assert_eq!(&buffer[..11], b"hello world");
+}
+
+#[test]
+fn reassembles_segments_with_multiple_holes() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let input = common::test_data(4000);
+    assert_eq!(client.send(&input[..1000], now), Ok(1000));
+    assert_eq!(client.send(&input[1000..2000], now), Ok(1000));
+    assert_eq!(client.send(&input[2000..3000], now), Ok(1000));
+    assert_eq!(client.send(&input[3000..], now), Ok(1000));
+    let segments: Vec<_> = std::iter::from_fn(|| client.poll_transmit()).collect();
+
+    // 4, 2 の順に届き、1 と 3 の位置に穴があく
+    server.handle_segment(&segments[3], now);
+    server.handle_segment(&segments[1], now);
+    assert_eq!(server.reassembly_queue.len(), 2000);
+    assert_eq!(server.recv(&mut [0; 16], now), Ok(None));
+    // 2 の再送が、3 の先頭と重なって届く
+    let overlapping = data_from(&client, segments[1].get_seq(), &input[1000..2500]);
+    server.handle_segment(&overlapping, now);
+    assert_eq!(server.reassembly_queue.ranges().count(), 2);
+    assert_eq!(server.reassembly_queue.len(), 2500);
+
+    server.handle_segment(&segments[0], now);
+    assert_eq!(server.recv_param.next, segments[2].get_seq() + 500);
+    server.handle_segment(&segments[2], now);
+    assert!(server.reassembly_queue.is_empty());
+    assert_eq!(server.recv_param.next, client.send_param.next);
+
+    let mut output = vec![0; 4000];
+    assert_eq!(server.recv(&mut output, now), Ok(Some(4000)));
+    assert_eq!(output, input);
+}
+
+/// 輻輳ウィンドウで送信を制限しない輻輳制御。受信ウィンドウを超えて送るテストで使う。
+#[derive(Debug)]
+struct Unlimited;
+
+impl CongestionControl for Unlimited {
+    fn on_ack(&mut self, _acked: usize, _rtt: Option<Duration>, _now: Duration) {}
+    fn on_loss(&mut self, _flight_size: usize, _now: Duration) {}
+    fn on_rto(&mut self, _flight_size: usize, _now: Duration) {}
+    fn cwnd(&self) -> usize {
+        usize::MAX
+    }
+    fn ssthresh(&self) -> usize {
+        usize::MAX
+    }
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+#[test]
+fn trims_segment_beyond_receive_window() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let input = common::test_data(5000);
+    // 受信バッファ 4380 バイトを超える分は、ウィンドウを無視して送ったことにする
+    client.send_param.window = 5000;
+    client.send_buffer_size = 5000;
+    client.congestion = Box::new(Unlimited);
+    assert_eq!(client.send(&input, now), Ok(5000));
+    let segments: Vec<_> = std::iter::from_fn(|| client.poll_transmit()).collect();
+    for segment in segments.iter().rev() {
+        server.handle_segment(segment, now);
+    }
+    assert_eq!(server.recv_param.window, 0);
+    assert_eq!(server.recv_param.next, segments[0].get_seq() + 4380);
+    assert!(server.reassembly_queue.is_empty());
+
+    let mut output = vec![0; 5000];
+    assert_eq!(server.recv(&mut output, now), Ok(Some(4380)));
+    assert_eq!(output[..4380], input[..4380]);
+}
+
+#[test]
+fn keeps_partially_acked_segment() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    assert_eq!(client.send(b"hello world", now), Ok(11));
+    let data = client.poll_transmit().unwrap();
+
+    let mut ack = TCPPacket::new(0);
+    ack.set_src(SERVER_PORT);
+    ack.set_dest(CLIENT_PORT);
+    ack.set_flag(tcpflags::ACK);
+    ack.set_seq(server.send_param.next);
+    ack.set_ack(data.get_seq() + 5);
+    ack.set_window_size(server.recv_param.window as u16);
+    client.handle_segment(&ack, now);
+    assert_eq!(client.retransmission_queue.len(), 1);
+
+    // 残りの部分は再送で届く
+    client.handle_timeout(Duration::from_secs(3));
+    let retransmitted = client.poll_transmit().unwrap();
+    assert_eq!(retransmitted.get_seq(), data.get_seq());
+    server.handle_segment(&data, now);
+    server.handle_segment(&retransmitted, now);
+    exchange(&mut client, &mut server, now);
+    assert!(client.retransmission_queue.is_empty());
+    let mut buffer = [0; 16];
+    assert_eq!(server.recv(&mut buffer, now), Ok(Some(11)));
+    assert_eq!(&buffer[..11], b"hello world");
+}
+
+#[test]
+fn retransmits_after_timeout() {
+    let (mut client, mut server) = establish_without_timestamps(Duration::ZERO);
+    assert_eq!(client.send(b"hello", Duration::ZERO), Ok(5));
+    // 最初の送信と1回目の再送は失われたことにする
+    let lost = client.poll_transmit().unwrap();
+
+    // ハンドシェイクで計測した RTT はほぼ 0 なので、RTO は下限の 1 秒になる
+    assert_eq!(client.stats.rtt.rto, Duration::from_secs(1));
+    client.handle_timeout(Duration::from_millis(999));
+    assert!(client.poll_transmit().is_none());
+    client.handle_timeout(Duration::from_secs(1));
+    assert_eq!(client.poll_transmit().unwrap().get_seq(), lost.get_seq());
+
+    // 2回目の再送までは2倍の時間待つ
+    client.handle_timeout(Duration::from_millis(2999));
+    assert!(client.poll_transmit().is_none());
+    let now = Duration::from_secs(3);
+    client.handle_timeout(now);
+    let retransmitted = client.poll_transmit().unwrap();
+    assert_eq!(retransmitted.get_seq(), lost.get_seq());
+    assert_eq!(client.stats.retransmissions, 2);
+    server.handle_segment(&retransmitted, now);
+    exchange(&mut client, &mut server, now);
+
+    let mut buffer = [0; 16];
+    assert_eq!(server.recv(&mut buffer, now), Ok(Some(5)));
+    assert_eq!(&buffer[..5], b"hello");
+    assert!(client.retransmission_queue.is_empty());
+    // 再送したセグメントの ACK では RTT を計測しないので、2倍にした RTO のまま
+    assert_eq!(client.stats.rtt.srtt, Some(Duration::ZERO));
+    assert_eq!(client.stats.rtt.rto, Duration::from_secs(4));
+
+    // 後から送信したセグメントも、2倍にした RTO が経過するまで再送しない
+    assert_eq!(client.send(b"world", now), Ok(5));
+    client.poll_transmit();
+    client.handle_timeout(now + Duration::from_millis(3999));
+    assert!(client.poll_transmit().is_none());
+    client.handle_timeout(now + Duration::from_secs(4));
+    assert!(client.poll_transmit().is_some());
+    assert_eq!(client.stats.rtt.rto, Duration::from_secs(8));
+}
+
+#[test]
+fn limits_send_to_congestion_window_after_timeout() {
+    let (mut client, mut server) = establish_without_timestamps(Duration::ZERO);
+    // 受信したデータを読み込まなくても、受信ウィンドウで送信が止まらないようにしておく
+    server.set_recv_buffer_size(2 * 4380);
+    let lost = send_segments(&mut client, 1460, 3);
+    assert_eq!(client.stats.cwnd, 4380);
+
+    let now = Duration::from_secs(1);
+    client.handle_timeout(now);
+    // 再送タイムアウトで、1セグメントからスロースタートし直す
+    assert_eq!(client.stats.cwnd, 1460);
+    assert_eq!(client.stats.ssthresh, 2920);
+    server.handle_segment(&client.poll_transmit().unwrap(), now);
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.stats.cwnd, 2920);
+
+    // 受信ウィンドウは空いているが、残りの 2 セグメントで輻輳ウィンドウが埋まっている
+    let input = common::test_data(1460);
+    assert_eq!(client.send(&input, now), Ok(0));
+    server.handle_segment(&lost[1], now);
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.send(&input, now), Ok(1460));
+}
+
+#[test]
+fn reduces_ssthresh_only_on_first_timeout() {
+    let (mut client, _server) = establish_without_timestamps(Duration::ZERO);
+    // CUBIC は cwnd から ssthresh を決めるので、2回目のタイムアウトでも下げると 1 セグメントの cwnd から計算し直してしまう
+    client.set_congestion_control(CongestionAlgorithm::Cubic);
+    send_segments(&mut client, 1460, 3);
+
+    client.handle_timeout(Duration::from_secs(1));
+    assert!(client.poll_transmit().is_some());
+    assert_eq!(client.stats.cwnd, 1460);
+    assert_eq!(client.stats.ssthresh, 3065);
+
+    // 同じセグメントが2回目のタイムアウトで再送されても、ssthresh は変えない
+    client.handle_timeout(Duration::from_secs(3));
+    assert!(client.poll_transmit().is_some());
+    assert_eq!(client.stats.retransmissions, 2);
+    assert_eq!(client.stats.cwnd, 1460);
+    assert_eq!(client.stats.ssthresh, 3065);
+}
+
+#[test]
+fn reduces_congestion_window_on_fast_retransmit() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let segments = send_segments(&mut client, 1000, 4);
+    for segment in &segments[1..] {
+        server.handle_segment(segment, now);
+    }
+    common::deliver(&mut server, &mut client, now);
+    assert_eq!(client.stats.fast_retransmissions, 1);
+    // 送信中の 4000 バイトの半分は 2 セグメントに満たない
+    assert_eq!(client.stats.ssthresh, 2920);
+    assert_eq!(client.stats.cwnd, 2920);
+
+    // リカバリを終える ACK では cwnd を増やさない
+    server.handle_segment(&client.poll_transmit().unwrap(), now);
+    exchange(&mut client, &mut server, now);
+    assert!(client.retransmission_queue.is_empty());
+    assert_eq!(client.stats.cwnd, 2920);
+}
+
+#[test]
+fn paces_segments_with_bbr() {
+    let now = Duration::ZERO;
+    let (mut client, _) = establish_without_timestamps(now);
+    client.set_congestion_control(CongestionAlgorithm::Bbr);
+    let input = common::test_data(4380);
+    // 最初のセグメントはすぐに送り、次のセグメントは送信レートで 1460 バイト送る時間だけ待つ
+    assert_eq!(client.send(&input, now), Ok(1460));
+    let interval = Duration::from_secs_f64(1460.0 / client.stats.pacing_rate.unwrap() as f64);
+    assert_eq!(client.pacing_delay(now), interval);
+    assert_eq!(client.send(&input[1460..], now + interval / 2), Ok(0));
+    assert_eq!(client.send(&input[1460..], now + interval), Ok(1460));
+    assert_eq!(client.pacing_delay(now + interval), interval);
+
+    // ペーシングしない輻輳制御では待たない
+    client.set_congestion_control(CongestionAlgorithm::Reno);
+    assert_eq!(client.pacing_delay(now), Duration::ZERO);
+    assert_eq!(client.send(&input[2920..], now), Ok(1460));
+}
+
+#[test]
+fn measures_rtt_from_acked_segments() {
+    let (mut client, mut server) = establish(Duration::ZERO);
+    for i in 1..=10 {
+        let sent = Duration::from_secs(i * 10);
+        assert_eq!(client.send(b"hello", sent), Ok(5));
+        common::deliver(&mut client, &mut server, sent);
+        common::deliver(&mut server, &mut client, sent + Duration::from_millis(1500));
+    }
+    let srtt = client.stats.rtt.srtt.unwrap();
+    assert!(srtt > Duration::from_millis(1000) && srtt < Duration::from_millis(1500));
+    assert!(client.stats.rtt.rto > srtt);
+}
+
+#[test]
+fn measures_rtt_of_retransmitted_segment_with_timestamps() {
+    let (mut client, mut server) = establish(Duration::ZERO);
+    assert!(client.timestamps);
+    assert!(server.timestamps);
+    assert_eq!(client.send(b"hello", Duration::ZERO), Ok(5));
+    client.poll_transmit();
+
+    // 再送したセグメントには、再送した時刻のタイムスタンプを付け直す
+    let now = Duration::from_secs(1);
+    client.handle_timeout(now);
+    let retransmitted = client.poll_transmit().unwrap();
+    assert!(retransmitted.options().any(|option| option
+        == TcpOption::Timestamps {
+            value: 1000,
+            echo_reply: 0
+        }));
+    server.handle_segment(&retransmitted, now + Duration::from_millis(100));
+    common::deliver(&mut server, &mut client, now + Duration::from_millis(200));
+    // 再送したセグメントの ACK でも、返ってきたタイムスタンプから RTT を計測する
+    assert_eq!(client.stats.rtt.srtt, Some(Duration::from_millis(200) / 8));
+}
+
+#[test]
+fn removes_sack_blocks_from_retransmitted_segment() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let segments = send_segments(&mut client, 500, 2);
+    server.handle_segment(&segments[1], now);
+    server.poll_transmit();
+    assert_eq!(server.send(b"hello", now), Ok(5));
+    let sent = server.poll_transmit().unwrap();
+    assert!(sent
+        .options()
+        .any(|option| matches!(option, TcpOption::Sack(_))));
+
+    // 再送するときには受信状態が変わっているかもしれないので、送信したときの SACK ブロックは付けない
+    let now = Duration::from_secs(1);
+    server.handle_timeout(now);
+    let retransmitted = server.poll_transmit().unwrap();
+    assert_eq!(retransmitted.get_seq(), sent.get_seq());
+    assert_eq!(retransmitted.payload(), b"hello");
+    assert_eq!(
+        retransmitted.options().collect::<Vec<_>>(),
+        vec![TcpOption::Timestamps {
+            value: 1000,
+            echo_reply: 0
+        }]
+    );
+    client.handle_segment(&retransmitted, now);
+    assert_eq!(client.recv(&mut [0; 16], now), Ok(Some(5)));
+}
+
+#[test]
+fn drops_old_duplicate_by_timestamp() {
+    let (mut client, mut server) = establish(Duration::ZERO);
+    let now = Duration::from_secs(2);
+    assert_eq!(client.send(b"hello", now), Ok(5));
+    common::deliver(&mut client, &mut server, now);
+    server.poll_transmit();
+
+    // seq は受信ウィンドウに収まっているが、タイムスタンプは最後に受け取ったものより古い
+    let mut old = data_from(&client, client.send_param.next, b"world");
+    old.set_options(&[TcpOption::Timestamps {
+        value: 1000,
+        echo_reply: 0,
+    }]);
+    server.handle_segment(&old, now);
+    assert_eq!(server.stats.dropped_paws, 1);
+    assert_eq!(server.recv(&mut [0; 16], now), Ok(Some(5)));
+    // 破棄した場合も、次に受信したい seq を ACK で伝える
+    let ack = server.poll_transmit().unwrap();
+    assert_eq!(ack.get_ack(), client.send_param.next);
+    assert!(ack.options().any(|option| option
+        == TcpOption::Timestamps {
+            value: 2000,
+            echo_reply: 2000
+        }));
+}
+
+#[test]
+fn does_not_use_timestamps_when_peer_omits_option() {
+    let (_, mut syn) = client_syn();
+    syn.set_options(&[TcpOption::Mss(1460), TcpOption::SackPermitted]);
+    match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), Duration::ZERO) {
+        AcceptOutcome::Accepted(mut server) => {
+            assert!(!server.timestamps);
+            assert!(server
+                .poll_transmit()
+                .unwrap()
+                .options()
+                .all(|option| !matches!(option, TcpOption::Timestamps { .. })));
+        }
+        _ => panic!("SYN was not accepted"),
+    }
+}
+
+/// client から size バイトずつ count 個のセグメントを送信し、送信したセグメントを返す。
+fn send_segments(client: &mut Connection, size: usize, count: usize) -> Vec<TCPPacket> {
+    let input = common::test_data(size * count);
+    for chunk in input.chunks(size) {
+        assert_eq!(client.send(chunk, Duration::ZERO), Ok(size));
+    }
+    std::iter::from_fn(|| client.poll_transmit()).collect()
+}
+
+#[test]
+fn fast_retransmits_on_duplicate_acks() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let segments = send_segments(&mut client, 1000, 4);
+    // 最初のセグメントが失われ、後続の3つに対して重複 ACK が返る
+    for segment in &segments[1..] {
+        server.handle_segment(segment, now);
+    }
+    common::deliver(&mut server, &mut client, now);
+    let retransmitted = client.poll_transmit().unwrap();
+    assert_eq!(retransmitted.get_seq(), segments[0].get_seq());
+    assert_eq!(client.stats.fast_retransmissions, 1);
+
+    server.handle_segment(&retransmitted, now);
+    exchange(&mut client, &mut server, now);
+    assert!(client.retransmission_queue.is_empty());
+    assert_eq!(client.stats.retransmissions, 0);
+    assert_eq!(server.recv(&mut [0; 4000], now), Ok(Some(4000)));
+}
+
+#[test]
+fn retransmits_on_partial_ack_during_fast_recovery() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    // SACK を使わなければ、partial ACK が届くまで2つ目のロスは分からない
+    client.sack_permitted = false;
+    server.sack_permitted = false;
+    let segments = send_segments(&mut client, 500, 8);
+    // 1つ目と3つ目が失われる
+    for (i, segment) in segments.iter().enumerate() {
+        if i != 0 && i != 2 {
+            server.handle_segment(segment, now);
+        }
+    }
+    common::deliver(&mut server, &mut client, now);
+    let retransmitted = client.poll_transmit().unwrap();
+    assert_eq!(retransmitted.get_seq(), segments[0].get_seq());
+    assert!(client.poll_transmit().is_none());
+
+    // 3つ目の手前までの partial ACK で、3つ目をすぐに再送する
+    server.handle_segment(&retransmitted, now);
+    common::deliver(&mut server, &mut client, now);
+    let retransmitted = client.poll_transmit().unwrap();
+    assert_eq!(retransmitted.get_seq(), segments[2].get_seq());
+    assert_eq!(client.stats.fast_retransmissions, 2);
+
+    server.handle_segment(&retransmitted, now);
+    exchange(&mut client, &mut server, now);
+    assert!(client.retransmission_queue.is_empty());
+    assert_eq!(client.stats.retransmissions, 0);
+    assert_eq!(server.recv(&mut [0; 4000], now), Ok(Some(4000)));
+}
+
+#[test]
+fn sends_new_segments_during_fast_recovery_without_sack() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish_without_timestamps(now);
+    client.sack_permitted = false;
+    server.sack_permitted = false;
+    client.send_buffer_size = 4 * 4380;
+    server.set_recv_buffer_size(4 * 4380);
+    // 広げた受信ウィンドウを先に伝えておき、重複 ACK がウィンドウの更新とみなされないようにする
+    client.handle_segment(&data_from(&server, server.send_param.next, &[]), now);
+    let segments = send_segments(&mut client, 1000, 4);
+    for segment in &segments[1..] {
+        server.handle_segment(segment, now);
+    }
+    common::deliver(&mut server, &mut client, now);
+    assert_eq!(
+        client.poll_transmit().unwrap().get_seq(),
+        segments[0].get_seq()
+    );
+    // ssthresh に、3つの重複 ACK の分のセグメントを足した大きさまで広げる
+    assert_eq!(client.stats.ssthresh, 2920);
+    assert_eq!(client.stats.cwnd, 2920 + 3 * 1460);
+
+    // 送信中の 4000 バイトを超えた分で、新しいセグメントを送れる
+    let input = common::test_data(4000);
+    assert_eq!(client.send(&input, now), Ok(3300));
+    let new_segments: Vec<_> = std::iter::from_fn(|| client.poll_transmit()).collect();
+    assert_eq!(new_segments.len(), 3);
+    // 新しいセグメントへの重複 ACK で、さらにウィンドウを広げる
+    server.handle_segment(&new_segments[0], now);
+    common::deliver(&mut server, &mut client, now);
+    assert_eq!(client.stats.cwnd, 2920 + 4 * 1460);
+    assert_eq!(client.send(&input[3300..], now), Ok(700));
+    assert_eq!(client.stats.fast_retransmissions, 1);
+
+    // 全て ack されたら、ssthresh まで戻す
+    server.handle_segment(&segments[0], now);
+    for segment in &new_segments[1..] {
+        server.handle_segment(segment, now);
+    }
+    server.handle_segment(&client.poll_transmit().unwrap(), now);
+    exchange(&mut client, &mut server, now);
+    assert!(client.retransmission_queue.is_empty());
+    assert_eq!(client.stats.cwnd, 2920);
+    assert_eq!(server.recv(&mut [0; 8000], now), Ok(Some(8000)));
+}
+
+#[test]
+fn deflates_window_on_partial_ack_without_sack() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish_without_timestamps(now);
+    client.sack_permitted = false;
+    server.sack_permitted = false;
+    server.set_recv_buffer_size(4 * 4380);
+    client.handle_segment(&data_from(&server, server.send_param.next, &[]), now);
+    let segments = send_segments(&mut client, 500, 8);
+    // 1つ目と3つ目が失われる
+    for (i, segment) in segments.iter().enumerate() {
+        if i != 0 && i != 2 {
+            server.handle_segment(segment, now);
+        }
+    }
+    common::deliver(&mut server, &mut client, now);
+    // 4000 バイトの半分が ssthresh になり、6つの重複 ACK の分だけ広がっている
+    assert_eq!(client.stats.ssthresh, 2920);
+    assert_eq!(client.stats.cwnd, 2920 + 6 * 1460);
+
+    // 1000 バイトの partial ACK では、ack された分だけ縮める。1セグメントに満たないので足し戻さない
+    server.handle_segment(&client.poll_transmit().unwrap(), now);
+    common::deliver(&mut server, &mut client, now);
+    assert_eq!(client.stats.cwnd, 2920 + 6 * 1460 - 1000);
+}
+
+#[test]
+fn negotiates_sack_permitted() {
+    let (client, server) = establish(Duration::ZERO);
+    assert!(client.sack_permitted);
+    assert!(server.sack_permitted);
+
+    // SACK-permitted を送らない相手には、SYN|ACK でも送らない
+    let (_, mut syn) = client_syn();
+    assert_eq!(
+        syn.options().collect::<Vec<_>>(),
+        vec![
+            TcpOption::Mss(1460),
+            TcpOption::WindowScale(0),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps {
+                value: 0,
+                echo_reply: 0
+            }
+        ]
+    );
+    syn.set_options(&[TcpOption::Mss(1460)]);
+    match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), Duration::ZERO) {
+        AcceptOutcome::Accepted(mut server) => {
+            assert!(!server.sack_permitted);
+            assert_eq!(
+                server
+                    .poll_transmit()
+                    .unwrap()
+                    .options()
+                    .collect::<Vec<_>>(),
+                vec![TcpOption::Mss(1460)]
+            );
+        }
+        _ => panic!("SYN was not accepted"),
+    }
+}
+
+#[test]
+fn segments_to_peer_mss() {
+    let now = Duration::ZERO;
+    let mut client = Connection::new(
+        CLIENT_ADDR,
+        SERVER_ADDR,
+        CLIENT_PORT,
+        SERVER_PORT,
+        TcpStatus::SynSent,
+    );
+    client.local_mss = Connection::mss_for_mtu(1000);
+    client.open(SeqNum::new(1000), now);
+    let syn = client.poll_transmit().unwrap();
+    assert_eq!(syn.options().next(), Some(TcpOption::Mss(960)));
+    let mut server = match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), now) {
+        AcceptOutcome::Accepted(server) => *server,
+        _ => panic!("SYN was not accepted"),
+    };
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.send_mss(), 960);
+    assert_eq!(server.send_mss(), 960);
+    // 初期の輻輳ウィンドウも相手の MSS で決まる
+    assert_eq!(server.stats.cwnd, 4 * 960);
+
+    let input = common::test_data(3000);
+    assert_eq!(server.send(&input, now), Ok(3000));
+    let sizes: Vec<_> = std::iter::from_fn(|| server.poll_transmit())
+        .map(|packet| packet.payload().len())
+        .collect();
+    // タイムスタンプオプションの 12 バイトを除いた分をペイロードにする
+    assert_eq!(sizes, [948, 948, 948, 156]);
+}
+
+#[test]
+fn clamps_tiny_peer_mss() {
+    let now = Duration::ZERO;
+    let (mut client, syn) = client_syn();
+    let mut server = match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), now) {
+        AcceptOutcome::Accepted(server) => *server,
+        _ => panic!("SYN was not accepted"),
+    };
+    let mut syn_ack = server.poll_transmit().unwrap();
+    let options: Vec<_> = syn_ack
+        .options()
+        .map(|option| match option {
+            TcpOption::Mss(_) => TcpOption::Mss(8),
+            option => option,
+        })
+        .collect();
+    syn_ack.set_options(&options);
+    client.handle_segment(&syn_ack, now);
+    assert_eq!(client.status, TcpStatus::Established);
+    assert!(client.timestamps);
+    assert_eq!(client.send_param.mss, 48);
+    assert_eq!(client.send_mss(), 48);
+    assert!(client.stats.cwnd > 0);
+
+    // オプションを付けても、ペイロードを載せて送り続けられる
+    let input = common::test_data(100);
+    assert_eq!(client.send(&input, now), Ok(100));
+    let sizes: Vec<_> = std::iter::from_fn(|| client.poll_transmit())
+        .filter(|packet| !packet.payload().is_empty())
+        .map(|packet| packet.payload().len())
+        .collect();
+    assert_eq!(sizes, [36, 36, 28]);
+
+    // 小さすぎる MTU でも同じ下限にする
+    assert_eq!(Connection::mss_for_mtu(68), 48);
+}
+
+#[test]
+fn uses_default_mss_when_peer_omits_it() {
+    let now = Duration::ZERO;
+    let (_, mut syn) = client_syn();
+    syn.set_options(&[]);
+    let mut server = match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), now) {
+        AcceptOutcome::Accepted(server) => *server,
+        _ => panic!("SYN was not accepted"),
+    };
+    assert_eq!(server.send_param.mss, 536);
+    assert_eq!(server.send_mss(), 536);
+    // 自分の MSS は相手が省略しても伝える
+    let syn_ack = server.poll_transmit().unwrap();
+    assert_eq!(syn_ack.options().next(), Some(TcpOption::Mss(1460)));
+}
+
+#[test]
+fn negotiates_window_scale() {
+    let now = Duration::ZERO;
+    let mut client = Connection::new(
+        CLIENT_ADDR,
+        SERVER_ADDR,
+        CLIENT_PORT,
+        SERVER_PORT,
+        TcpStatus::SynSent,
+    );
+    client.set_recv_buffer_size(1 << 20);
+    client.open(SeqNum::new(1000), now);
+    let syn = client.poll_transmit().unwrap();
+    assert!(syn
+        .options()
+        .any(|option| option == TcpOption::WindowScale(5)));
+    // SYN のウィンドウはスケールしない
+    assert_eq!(syn.get_window_size(), u16::MAX);
+
+    let mut listener = common::listener();
+    listener.set_recv_buffer_size(1 << 18);
+    let mut server = match listener.accept(CLIENT_ADDR, &syn, SeqNum::new(5000), now) {
+        AcceptOutcome::Accepted(server) => *server,
+        _ => panic!("SYN was not accepted"),
+    };
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.send_param.window_shift, 3);
+    assert_eq!(server.send_param.window_shift, 5);
+    // ハンドシェイクの最後の ACK から、スケールしたウィンドウを受け取る
+    assert_eq!(server.send_param.window, 1 << 20);
+
+    let input = common::test_data(100_000);
+    assert_eq!(server.send(&input, now), Ok(server.stats.cwnd));
+    let segment = server.poll_transmit().unwrap();
+    assert_eq!(segment.get_window_size(), (1 << 18 >> 3) as u16);
+}
+
+#[test]
+fn does_not_scale_window_when_peer_omits_option() {
+    let now = Duration::ZERO;
+    let (_, mut syn) = client_syn();
+    syn.set_options(&[TcpOption::Mss(1460)]);
+    let mut listener = common::listener();
+    listener.set_recv_buffer_size(1 << 20);
+    let mut server = match listener.accept(CLIENT_ADDR, &syn, SeqNum::new(5000), now) {
+        AcceptOutcome::Accepted(server) => *server,
+        _ => panic!("SYN was not accepted"),
+    };
+    assert_eq!(server.recv_param.window_shift, 0);
+    let syn_ack = server.poll_transmit().unwrap();
+    assert_eq!(
+        syn_ack.options().collect::<Vec<_>>(),
+        vec![TcpOption::Mss(1460)]
+    );
+    // 広告できるのは 16 ビットに収まる分だけ
+    assert_eq!(syn_ack.get_window_size(), u16::MAX);
+}
+
+#[test]
+fn leaves_room_for_sack_option_in_data_segments() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish_without_timestamps(now);
+    let segments = send_segments(&mut client, 1000, 2);
+    // client の受信側に順序が揃っていないデータがあれば、データセグメントにも SACK ブロックを付ける
+    server.handle_segment(&segments[1], now);
+    server.poll_transmit();
+    assert_eq!(server.send(&common::test_data(2000), now), Ok(2000));
+    let data = server.poll_transmit().unwrap();
+    let sack = TcpOption::Sack(vec![(segments[1].get_seq(), segments[1].get_seq() + 1000)]);
+    assert_eq!(data.options().collect::<Vec<_>>(), vec![sack]);
+    // 10 バイトの SACK オプションを4バイト単位に埋めた分だけ、ペイロードを小さくする
+    assert_eq!(data.payload().len(), 1460 - 12);
+    assert_eq!(data.packet().len(), 20 + 1460);
+}
+
+#[test]
+fn acks_out_of_order_data_with_sack_blocks() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish_without_timestamps(now);
+    let segments = send_segments(&mut client, 500, 5);
+    let range = |i: usize| (segments[i].get_seq(), segments[i].get_seq() + 500);
+    server.handle_segment(&segments[3], now);
+    server.handle_segment(&segments[1], now);
+    server.handle_segment(&segments[2], now);
+    let acks: Vec<_> = std::iter::from_fn(|| server.poll_transmit()).collect();
+    // 最後に届いたセグメントを含むブロックを先頭にする
+    assert_eq!(
+        acks[0].options().collect::<Vec<_>>(),
+        vec![TcpOption::Sack(vec![range(3)])]
+    );
+    assert_eq!(
+        acks[1].options().collect::<Vec<_>>(),
+        vec![TcpOption::Sack(vec![range(1), range(3)])]
+    );
+    assert_eq!(
+        acks[2].options().collect::<Vec<_>>(),
+        vec![TcpOption::Sack(vec![(range(1).0, range(3).1)])]
+    );
+
+    // 穴が埋まれば SACK ブロックは付けない
+    server.handle_segment(&segments[0], now);
+    assert!(server.poll_transmit().unwrap().options().next().is_none());
+}
+
+#[test]
+fn retransmits_multiple_losses_with_sack() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let segments = send_segments(&mut client, 500, 8);
+    // 1つ目と3つ目が失われる
+    for (i, segment) in segments.iter().enumerate() {
+        if i != 0 && i != 2 {
+            server.handle_segment(segment, now);
+        }
+    }
+    common::deliver(&mut server, &mut client, now);
+    let sacked: Vec<_> = client
+        .retransmission_queue
+        .iter()
+        .map(|item| item.sacked)
+        .collect();
+    assert_eq!(sacked, [false, true, false, true, true, true, true, true]);
+    // partial ACK を待たずに、SACK で失われたと分かった2つのセグメントを再送する
+    let retransmitted: Vec<_> = std::iter::from_fn(|| client.poll_transmit()).collect();
+    assert_eq!(
+        retransmitted
+            .iter()
+            .map(|p| p.get_seq())
+            .collect::<Vec<_>>(),
+        [segments[0].get_seq(), segments[2].get_seq()]
+    );
+    assert_eq!(client.stats.fast_retransmissions, 2);
+
+    for segment in &retransmitted {
+        server.handle_segment(segment, now);
+    }
+    exchange(&mut client, &mut server, now);
+    assert!(client.retransmission_queue.is_empty());
+    assert_eq!(client.stats.retransmissions, 0);
+    assert_eq!(server.recv(&mut [0; 4000], now), Ok(Some(4000)));
+}
+
+#[test]
+fn forgets_sack_scoreboard_after_timeout() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let segments = send_segments(&mut client, 1000, 2);
+    server.handle_segment(&segments[1], now);
+    common::deliver(&mut server, &mut client, now);
+    assert!(client.retransmission_queue[1].sacked);
+
+    client.handle_timeout(Duration::from_secs(3));
+    assert_eq!(
+        client.poll_transmit().unwrap().get_seq(),
+        segments[0].get_seq()
+    );
+    assert!(client.retransmission_queue.iter().all(|item| !item.sacked));
+}
+
+#[test]
+fn updates_send_window_from_peer_segments() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    // ack が進まなくても、相手のウィンドウが小さくなれば送信を減らす
+    let mut update = data_from(&server, server.send_param.next, &[]);
+    update.set_window_size(1000);
+    client.handle_segment(&update, now);
+    assert_eq!(client.send_param.window, 1000);
+    assert_eq!(client.send(&common::test_data(4380), now), Ok(1000));
+
+    // 後から届いた古いセグメントのウィンドウでは更新しない
+    assert_eq!(server.send(b"hello", now), Ok(5));
+    let data = server.poll_transmit().unwrap();
+    let mut update = data_from(&server, server.send_param.next, &[]);
+    update.set_window_size(2000);
+    client.handle_segment(&update, now);
+    client.handle_segment(&data, now);
+    assert_eq!(client.send_param.window, 2000);
+    assert_eq!(client.send_param.wl1, server.send_param.next);
+}
+
+#[test]
+fn sends_window_update_when_window_opens_enough() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let input = common::test_data(4380);
+    assert_eq!(client.send(&input, now), Ok(4380));
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.send_param.window, 0);
+
+    // MSS に満たない分だけ読み込んでも、小さなウィンドウは広告しない
+    assert_eq!(server.recv(&mut [0; 1000], now), Ok(Some(1000)));
+    assert!(server.poll_transmit().is_none());
+    // データセグメントでも同様
+    assert_eq!(server.send(b"hello", now), Ok(5));
+    assert_eq!(server.poll_transmit().unwrap().get_window_size(), 0);
+
+    // 合わせて MSS 以上開いたら、ACK でウィンドウを伝える
+    assert_eq!(server.recv(&mut [0; 1000], now), Ok(Some(1000)));
+    let ack = server.poll_transmit().unwrap();
+    assert_eq!(ack.get_ack(), server.recv_param.next);
+    assert_eq!(ack.get_window_size(), 2000);
+    client.handle_segment(&ack, now);
+    assert_eq!(client.send_param.window, 2000);
+    assert!(events(&mut client).contains(&TCPEventKind::Acked));
+
+    // FIN を受け取った後は、もう相手から送られてこないので伝えない
+    client.close(now);
+    exchange(&mut client, &mut server, now);
+    assert_eq!(server.recv(&mut [0; 4380], now), Ok(Some(2380)));
+    assert!(server.poll_transmit().is_none());
+}
+
+#[test]
+fn probes_zero_window() {
+    let (mut client, mut server) = establish(Duration::ZERO);
+    let input = common::test_data(5000);
+    assert_eq!(client.send(&input, Duration::ZERO), Ok(4380));
+    exchange(&mut client, &mut server, Duration::ZERO);
+    assert_eq!(client.send_param.window, 0);
+    assert_eq!(client.send(&input[4380..], Duration::ZERO), Ok(0));
+
+    // 受信側が読み込んでウィンドウが開いたことを伝える ACK が、失われたことにする
+    assert_eq!(server.recv(&mut [0; 4380], Duration::ZERO), Ok(Some(4380)));
+    assert!(server.poll_transmit().is_some());
+    client.handle_timeout(Duration::from_millis(999));
+    assert!(client.poll_transmit().is_none());
+    let now = Duration::from_secs(1);
+    client.handle_timeout(now);
+    let probe = client.poll_transmit().unwrap();
+    assert_eq!(probe.get_seq(), client.send_param.unacked_seq - 1);
+    assert_eq!(client.stats.window_probes, 1);
+    server.handle_segment(&probe, now);
+    common::deliver(&mut server, &mut client, now);
+    assert_eq!(client.send_param.window, 4380);
+    assert!(events(&mut client).contains(&TCPEventKind::Acked));
+    assert_eq!(client.send(&input[4380..], now), Ok(620));
+
+    // ウィンドウが開いたので、もうプローブは送らない
+    exchange(&mut client, &mut server, now);
+    client.handle_timeout(Duration::from_secs(10));
+    assert!(client.poll_transmit().is_none());
+    assert_eq!(client.stats.window_probes, 1);
+}
+
+#[test]
+fn window_update_is_not_duplicate_ack() {
+    let now = Duration::ZERO;
+    let (mut client, server) = establish(now);
+    send_segments(&mut client, 1000, 4);
+    for window in [1000, 2000, 3000] {
+        let mut update = data_from(&server, server.send_param.next, &[]);
+        update.set_window_size(window);
+        client.handle_segment(&update, now);
+    }
+    assert!(client.poll_transmit().is_none());
+    assert_eq!(client.stats.fast_retransmissions, 0);
+}
+
+#[test]
+fn aborts_after_user_timeout() {
+    let (mut client, _server) = establish(Duration::ZERO);
+    client.user_timeout = Duration::from_secs(10);
+    assert_eq!(client.send(b"hello", Duration::ZERO), Ok(5));
+    // 相手には何も届かない
+    let mut now = Duration::ZERO;
+    while client.status == TcpStatus::Established {
+        now += Duration::from_millis(100);
+        client.handle_timeout(now);
+        while client.poll_transmit().is_some() {}
+    }
+    assert_eq!(now, Duration::from_secs(10));
+    // 1, 2, 4 秒後の3回再送している
+    assert_eq!(client.stats.retransmissions, 3);
+    assert_eq!(client.status, TcpStatus::Closed);
+    assert!(client.retransmission_queue.is_empty());
+    assert!(events(&mut client).contains(&TCPEventKind::ConnectionAborted));
+    assert_eq!(client.error, Some(TCPError::TimedOut));
+    assert_eq!(client.send(b"world", now), Err(TCPError::TimedOut));
+    assert_eq!(client.recv(&mut [0; 16], now), Err(TCPError::TimedOut));
+}
+
+#[test]
+fn closes_connection() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    client.close(now);
+    assert_eq!(client.status, TcpStatus::FinWait1);
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.status, TcpStatus::FinWait2);
+    assert_eq!(server.status, TcpStatus::CloseWait);
+    // FIN を受信済みなので、データがなければ EOF を返す
+    assert_eq!(server.recv(&mut [0; 16], now), Ok(Some(0)));
+
+    server.close(now);
+    assert_eq!(server.status, TcpStatus::LastAck);
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.status, TcpStatus::TimeWait);
+    assert!(events(&mut client).contains(&TCPEventKind::ConnectionClosed));
+    assert_eq!(server.status, TcpStatus::Closed);
+    assert!(events(&mut server).contains(&TCPEventKind::ConnectionClosed));
+    assert!(server.retransmission_queue.is_empty());
+}
+
+#[test]
+fn synsent_refused_by_rst() {
+    let (mut client, syn) = client_syn();
+    let rst = reset_segment(SERVER_ADDR, CLIENT_ADDR, &syn).unwrap();
+    client.handle_segment(&rst, Duration::ZERO);
+    assert_eq!(client.status, TcpStatus::Closed);
+    assert_eq!(client.error, Some(TCPError::ConnectionRefused));
+    assert_eq!(events(&mut client), [TCPEventKind::ConnectionAborted]);
+    assert!(client.retransmission_queue.is_empty());
+}
+
+#[test]
+fn synsent_ignores_rst_without_acceptable_ack() {
+    let (mut client, syn) = client_syn();
+    let mut rst = reset_segment(SERVER_ADDR, CLIENT_ADDR, &syn).unwrap();
+    rst.set_ack(syn.get_seq());
+    client.handle_segment(&rst, Duration::ZERO);
+    assert_eq!(client.status, TcpStatus::SynSent);
+    assert!(client.error.is_none());
+}
+
+/// from が送信したデータセグメントを、seq を指定して生成する。
+fn data_from(from: &Connection, seq: SeqNum, payload: &[u8]) -> TCPPacket {
+    let mut packet = TCPPacket::new(payload.len());
+    packet.set_src(from.local_port);
+    packet.set_dest(from.remote_port);
+    packet.set_flag(tcpflags::ACK);
+    packet.set_seq(seq);
+    packet.set_ack(from.recv_param.next);
+    packet.set_window_size((from.recv_param.window >> from.recv_param.window_shift) as u16);
+    packet.set_payload(payload);
+    packet
+}
+
+/// from が送信した RST を、seq を指定して生成する。
+fn rst_from(from: &Connection, seq: SeqNum) -> TCPPacket {
+    let mut ack = TCPPacket::new(0);
+    ack.set_src(from.remote_port);
+    ack.set_dest(from.local_port);
+    ack.set_flag(tcpflags::ACK);
+    ack.set_ack(seq);
+    reset_segment(from.local_addr, from.remote_addr, &ack).unwrap()
+}
+
+#[test]
+fn established_reset_by_in_window_rst() {
+    let (mut client, server) = establish(Duration::ZERO);
+    events(&mut client);
+    // 受信ウィンドウ内であれば、次に受信する seq と一致していなくても受け入れる
+    let rst = rst_from(&server, server.send_param.next + 100);
+    client.handle_segment(&rst, Duration::ZERO);
+    assert_eq!(client.status, TcpStatus::Closed);
+    assert_eq!(events(&mut client), [TCPEventKind::ConnectionAborted]);
+    assert_eq!(
+        client.send(b"hello", Duration::ZERO),
+        Err(TCPError::ConnectionReset)
+    );
+    assert_eq!(
+        client.recv(&mut [0; 16], Duration::ZERO),
+        Err(TCPError::ConnectionReset)
+    );
+
+    // CLOSED になったコネクションへのセグメントには RST を返す
+    let mut segment = rst.clone();
+    segment.set_flag(tcpflags::ACK);
+    client.handle_segment(&segment, Duration::ZERO);
+    assert_eq!(client.poll_transmit().unwrap().get_flag(), tcpflags::RST);
+}
+
+#[test]
+fn ignores_out_of_window_rst() {
+    let (mut client, server) = establish(Duration::ZERO);
+    for seq in [server.send_param.next - 1, server.send_param.next + 10_000] {
+        client.handle_segment(&rst_from(&server, seq), Duration::ZERO);
+        assert_eq!(client.status, TcpStatus::Established);
+        assert!(client.error.is_none());
+    }
+}
+
+#[test]
+fn answers_out_of_window_segment_with_ack() {
+    let now = Duration::ZERO;
+    let (client, mut server) = establish(now);
+    let next = server.recv_param.next;
+    for seq in [next - 100, next + 10_000] {
+        server.handle_segment(&data_from(&client, seq, b"stale"), now);
+        let ack = server.poll_transmit().unwrap();
+        assert_eq!(ack.get_flag(), tcpflags::ACK);
+        assert_eq!(ack.get_ack(), next);
+    }
+    assert_eq!(server.recv_param.next, next);
+    assert!(server.reassembly_queue.is_empty());
+    assert_eq!(server.stats.dropped_out_of_window, 2);
+    // RST には ACK を返さない
+    server.handle_segment(&rst_from(&client, next + 10_000), now);
+    assert!(server.poll_transmit().is_none());
+    assert_eq!(server.stats.dropped_out_of_window, 3);
+}
+
+#[test]
+fn answers_ack_for_unsent_data_with_ack() {
+    let now = Duration::ZERO;
+    let (client, mut server) = establish(now);
+    let mut segment = data_from(&client, client.send_param.next, b"hello");
+    segment.set_ack(server.send_param.next + 100);
+    server.handle_segment(&segment, now);
+    let ack = server.poll_transmit().unwrap();
+    assert_eq!(ack.get_seq(), server.send_param.next);
+    assert_eq!(ack.get_ack(), client.send_param.next);
+    assert_eq!(server.stats.dropped_bad_ack, 1);
+    assert_eq!(server.recv(&mut [0; 16], now), Ok(None));
+}
+
+#[test]
+fn processes_ack_when_receive_window_is_zero() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    let input = common::test_data(4380);
+    assert_eq!(client.send(&input, now), Ok(4380));
+    exchange(&mut client, &mut server, now);
+    assert_eq!(server.recv_param.window, 0);
+
+    // ウィンドウが 0 でも、データに載っている ACK は処理する
+    assert_eq!(server.send(b"hello", now), Ok(5));
+    common::deliver(&mut server, &mut client, now);
+    // client の ACK は失われたことにする
+    client.poll_transmit().unwrap();
+    client.send_param.window = 100;
+    assert_eq!(client.send(b"world", now), Ok(5));
+    let segment = client.poll_transmit().unwrap();
+    assert_eq!(segment.get_ack(), server.send_param.next);
+    server.handle_segment(&segment, now);
+    assert!(server.retransmission_queue.is_empty());
+    // ペイロードは受け取れない
+    let ack = server.poll_transmit().unwrap();
+    assert_eq!(ack.get_ack(), segment.get_seq());
+    assert_eq!(server.recv_param.next, segment.get_seq());
+}
+
+#[test]
+fn lastack_closes_on_rst() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    client.close(now);
+    exchange(&mut client, &mut server, now);
+    server.close(now);
+    // server の FIN が失われ、client は既に破棄されていたとする
+    server.poll_transmit().unwrap();
+    events(&mut server);
+    server.handle_segment(&rst_from(&client, client.send_param.next), now);
+    assert_eq!(server.status, TcpStatus::Closed);
+    assert!(server.error.is_none());
+    assert_eq!(events(&mut server), [TCPEventKind::ConnectionClosed]);
+}
+
+#[test]
+fn synrcvd_closes_on_rst() {
+    let (_, syn) = client_syn();
+    let mut server =
+        match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), Duration::ZERO) {
+            AcceptOutcome::Accepted(server) => *server,
+            _ => unreachable!(),
+        };
+    let mut rst = syn.clone();
+    rst.set_flag(tcpflags::RST);
+    rst.set_seq(syn.get_seq() + 1);
+    server.handle_segment(&rst, Duration::ZERO);
+    assert_eq!(server.status, TcpStatus::Closed);
+    assert!(server.retransmission_queue.is_empty());
+}
+
+#[test]
+fn active_closer_waits_in_time_wait() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    client.msl = Duration::from_secs(1);
+    client.close(now);
+    exchange(&mut client, &mut server, now);
+    server.close(now);
+    let fin = server.poll_transmit().unwrap();
+    client.handle_segment(&fin, now);
+    assert_eq!(client.status, TcpStatus::TimeWait);
+    assert!(events(&mut client).contains(&TCPEventKind::ConnectionClosed));
+    // 最初の ACK は失われたことにする
+    client.poll_transmit().unwrap();
+
+    // FIN が再送されてきたら ACK を再送し、2*MSL を数え直す
+    let now = Duration::from_millis(1500);
+    client.handle_segment(&fin, now);
+    let ack = client.poll_transmit().unwrap();
+    assert_eq!(ack.get_flag(), tcpflags::ACK);
+    assert_eq!(ack.get_ack(), fin.get_seq() + 1);
+    client.handle_timeout(Duration::from_millis(3000));
+    assert_eq!(client.status, TcpStatus::TimeWait);
+
+    // TIMEWAIT では RST も無視する
+    client.handle_segment(&rst_from(&server, client.recv_param.next), now);
+    assert_eq!(client.status, TcpStatus::TimeWait);
+
+    client.handle_timeout(Duration::from_millis(3500));
+    assert_eq!(client.status, TcpStatus::Closed);
+    assert!(client.error.is_none());
+}
+
+#[test]
+fn simultaneous_open() {
+    let now = Duration::ZERO;
+    let mut a = Connection::connect(
+        CLIENT_ADDR,
+        SERVER_ADDR,
+        CLIENT_PORT,
+        SERVER_PORT,
+        SeqNum::new(1000),
+        now,
+    );
+    let mut b = Connection::connect(
+        SERVER_ADDR,
+        CLIENT_ADDR,
+        SERVER_PORT,
+        CLIENT_PORT,
+        SeqNum::new(5000),
+        now,
+    );
+    // お互いの SYN が行き違う
+    let syn_a = a.poll_transmit().unwrap();
+    let syn_b = b.poll_transmit().unwrap();
+    a.handle_segment(&syn_b, now);
+    b.handle_segment(&syn_a, now);
+    assert_eq!(a.status, TcpStatus::SynRcvd);
+    assert_eq!(b.status, TcpStatus::SynRcvd);
+
+    // お互いの SYN|ACK で確立する
+    exchange(&mut a, &mut b, now);
+    assert_eq!(a.status, TcpStatus::Established);
+    assert_eq!(b.status, TcpStatus::Established);
+    assert!(events(&mut a).contains(&TCPEventKind::ConnectionCompleted));
+    assert!(events(&mut b).contains(&TCPEventKind::ConnectionCompleted));
+    assert!(a.retransmission_queue.is_empty());
+    assert!(b.retransmission_queue.is_empty());
+
+    assert_eq!(a.send(b"hello", now), Ok(5));
+    exchange(&mut a, &mut b, now);
+    let mut buffer = [0; 16];
+    assert_eq!(b.recv(&mut buffer, now), Ok(Some(5)));
+    assert_eq!(&buffer[..5], b"hello");
+}
+
+#[test]
+fn simultaneous_close() {
+    let now = Duration::ZERO;
+    let (mut client, mut server) = establish(now);
+    events(&mut client);
+    events(&mut server);
+    // お互いの FIN が行き違う
+    client.close(now);
+    server.close(now);
+    let fin_client = client.poll_transmit().unwrap();
+    let fin_server = server.poll_transmit().unwrap();
+    client.handle_segment(&fin_server, now);
+    server.handle_segment(&fin_client, now);
+    assert_eq!(client.status, TcpStatus::Closing);
+    assert_eq!(server.status, TcpStatus::Closing);
+    assert_eq!(client.recv(&mut [0; 16], now), Ok(Some(0)));
+    assert!(!events(&mut client).contains(&TCPEventKind::ConnectionClosed));
+
+    // お互いの ACK で TIMEWAIT に遷移する
+    exchange(&mut client, &mut server, now);
+    assert_eq!(client.status, TcpStatus::TimeWait);
+    assert_eq!(server.status, TcpStatus::TimeWait);
+    assert!(events(&mut client).contains(&TCPEventKind::ConnectionClosed));
+    assert!(events(&mut server).contains(&TCPEventKind::ConnectionClosed));
+}
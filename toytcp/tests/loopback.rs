@@ -0,0 +1,192 @@
+mod common;
+
+use common::{CLIENT_ADDR, CLIENT_PORT, SERVER_ADDR, SERVER_PORT};
+use pnet::packet::{tcp::TcpPacket, Packet};
+use std::thread;
+use std::time::Duration;
+use toytcp::congestion::CongestionAlgorithm;
+use toytcp::connection::{reset_segment, AcceptOutcome, Connection};
+use toytcp::error::TCPError;
+use toytcp::link::Link;
+use toytcp::loopback::LoopbackLink;
+use toytcp::packet::TCPPacket;
+use toytcp::seqnum::SeqNum;
+use toytcp::simulator::{Impairment, Rule, SimulatorConfig, SimulatorLink};
+use toytcp::tcp::TCP;
+use toytcp::tcpflags;
+
+#[test]
+fn echo() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client = TCP::new(client_link);
+    let server = TCP::new(server_link);
+    let listening_socket = server.listen(SERVER_ADDR, SERVER_PORT).unwrap();
+
+    // examples/echoserver.rs と同じ流れ
+    let server_thread = thread::spawn(move || {
+        let connected_socket = server.accept(listening_socket).unwrap();
+        let mut buffer = [0; 1024];
+        loop {
+            let nbytes = server.recv(connected_socket, &mut buffer).unwrap();
+            if nbytes == 0 {
+                server.close(connected_socket).unwrap();
+                return;
+            }
+            server.send(connected_socket, &buffer[..nbytes]).unwrap();
+        }
+    });
+
+    let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+    for line in ["hello\n", "toytcp\n"] {
+        client.send(sock_id, line.as_bytes()).unwrap();
+        let mut buffer = vec![0; 1500];
+        let n = client.recv(sock_id, &mut buffer).unwrap();
+        assert_eq!(&buffer[..n], line.as_bytes());
+    }
+    client.close(sock_id).unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn file_transfer() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client = TCP::new(client_link);
+    let server_thread = common::spawn_file_server(TCP::new(server_link));
+
+    let input = common::test_data(50_000);
+    common::send_file(&client, &input);
+    assert_eq!(server_thread.join().unwrap(), input);
+}
+
+#[test]
+fn file_transfer_with_large_buffers() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client = TCP::new(client_link);
+    let server = TCP::new(server_link);
+    for tcp in [&client, &server] {
+        tcp.set_send_buffer_size(1 << 20);
+        tcp.set_recv_buffer_size(1 << 20);
+    }
+    let server_thread = common::spawn_file_server(server);
+
+    let input = common::test_data(1_000_000);
+    common::send_file(&client, &input);
+    assert_eq!(server_thread.join().unwrap(), input);
+}
+
+#[test]
+fn file_transfer_over_small_mtu() {
+    let (client_link, server_link) = LoopbackLink::pair_with_mtu(CLIENT_ADDR, SERVER_ADDR, 576);
+    // MSS の 536 バイトを超えるセグメントがあれば、履歴に残る
+    let client_link = SimulatorLink::new(client_link, SimulatorConfig::default());
+    client_link.add_rule(Rule::once(Impairment::Duplicate, |packet| {
+        packet.payload().len() > 536
+    }));
+    let client = TCP::new(client_link.clone());
+    let server_thread = common::spawn_file_server(TCP::new(server_link));
+
+    let input = common::test_data(10_000);
+    common::send_file(&client, &input);
+    assert_eq!(server_thread.join().unwrap(), input);
+    assert!(client_link.history().is_empty());
+}
+
+#[test]
+fn file_transfer_with_each_congestion_control() {
+    for algorithm in [
+        CongestionAlgorithm::Reno,
+        CongestionAlgorithm::Cubic,
+        CongestionAlgorithm::Bbr,
+    ] {
+        let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+        let client = TCP::new(client_link);
+        let server_thread = common::spawn_file_server(TCP::new(server_link));
+
+        let input = common::test_data(50_000);
+        let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+        client.set_congestion_control(sock_id, algorithm).unwrap();
+        client.send(sock_id, &input).unwrap();
+        let stats = client.stats(sock_id).unwrap();
+        client.close(sock_id).unwrap();
+        assert_eq!(server_thread.join().unwrap(), input, "{:?}", algorithm);
+        // ペーシングするのは BBR だけ
+        assert_eq!(
+            stats.pacing_rate.is_some(),
+            algorithm == CongestionAlgorithm::Bbr
+        );
+    }
+}
+
+#[test]
+fn resets_segment_to_closed_port() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let _server = TCP::new(server_link);
+
+    // リスニングソケットがないポートに SYN を送る
+    let syn = Connection::connect(
+        CLIENT_ADDR,
+        SERVER_ADDR,
+        CLIENT_PORT,
+        SERVER_PORT,
+        SeqNum::new(1000),
+        Duration::ZERO,
+    )
+    .poll_transmit()
+    .unwrap();
+    client_link.send_to(syn.packet(), SERVER_ADDR).unwrap();
+    let rst = recv_segment(&client_link);
+    assert_eq!(rst.get_flag(), tcpflags::RST | tcpflags::ACK);
+    assert_eq!(rst.get_ack(), SeqNum::new(1001));
+}
+
+#[test]
+fn connect_to_closed_port_is_refused() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client = TCP::new(client_link);
+    let _server = TCP::new(server_link);
+    let error = client.connect(SERVER_ADDR, SERVER_PORT).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<TCPError>(),
+        Some(&TCPError::ConnectionRefused)
+    );
+}
+
+/// link からセグメントを1つ受信する。
+fn recv_segment(link: &LoopbackLink) -> TCPPacket {
+    let (_, _, segment) = link.recv().unwrap();
+    TCPPacket::from(TcpPacket::new(&segment).unwrap())
+}
+
+#[test]
+fn recv_and_send_fail_after_reset() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client = TCP::new(client_link);
+
+    // server 側はスレッドを使わずに Connection を直接動かす
+    let client_thread = thread::spawn(move || {
+        let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+        let recv_error = client.recv(sock_id, &mut [0; 16]).unwrap_err();
+        let send_error = client.send(sock_id, b"hello").unwrap_err();
+        client.close(sock_id).unwrap();
+        (recv_error, send_error)
+    });
+    let syn = recv_segment(&server_link);
+    let mut server =
+        match common::listener().accept(CLIENT_ADDR, &syn, SeqNum::new(5000), Duration::ZERO) {
+            AcceptOutcome::Accepted(server) => *server,
+            _ => panic!("SYN was not accepted"),
+        };
+    let syn_ack = server.poll_transmit().unwrap();
+    server_link.send_to(syn_ack.packet(), CLIENT_ADDR).unwrap();
+    let ack = recv_segment(&server_link);
+    let rst = reset_segment(SERVER_ADDR, CLIENT_ADDR, &ack).unwrap();
+    server_link.send_to(rst.packet(), CLIENT_ADDR).unwrap();
+
+    let (recv_error, send_error) = client_thread.join().unwrap();
+    for error in [recv_error, send_error] {
+        assert_eq!(
+            error.downcast_ref::<TCPError>(),
+            Some(&TCPError::ConnectionReset)
+        );
+    }
+}
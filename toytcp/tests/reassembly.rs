@@ -0,0 +1,54 @@
+use toytcp::reassembly::ReassemblyQueue;
+use toytcp::seqnum::SeqNum;
+
+fn ranges(queue: &ReassemblyQueue) -> Vec<(u32, u32)> {
+    queue
+        .ranges()
+        .map(|(left, right)| (left.get(), right.get()))
+        .collect()
+}
+
+#[test]
+fn keeps_holes_between_segments() {
+    let mut queue = ReassemblyQueue::new();
+    queue.insert(SeqNum::new(30), b"de");
+    queue.insert(SeqNum::new(10), b"ab");
+    queue.insert(SeqNum::new(20), b"c");
+    assert_eq!(ranges(&queue), [(10, 12), (20, 21), (30, 32)]);
+    assert_eq!(queue.len(), 5);
+    assert_eq!(queue.pop(SeqNum::new(5)), None);
+}
+
+#[test]
+fn trims_overlapping_data_and_merges() {
+    let mut queue = ReassemblyQueue::new();
+    queue.insert(SeqNum::new(12), b"cd");
+    queue.insert(SeqNum::new(16), b"gh");
+    // 既に保管している部分は、後から届いたデータで上書きしない
+    queue.insert(SeqNum::new(10), b"ABCDEFGHIJ");
+    assert_eq!(ranges(&queue), [(10, 20)]);
+    assert_eq!(queue.pop(SeqNum::new(10)).unwrap(), b"ABcdEFghIJ");
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn pops_only_contiguous_data() {
+    let mut queue = ReassemblyQueue::new();
+    queue.insert(SeqNum::new(10), b"abc");
+    queue.insert(SeqNum::new(20), b"xyz");
+    // 10..12 は受信済みになったので捨てる
+    assert_eq!(queue.pop(SeqNum::new(12)).unwrap(), b"c");
+    assert_eq!(queue.pop(SeqNum::new(13)), None);
+    assert_eq!(queue.pop(SeqNum::new(25)), None);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn handles_wrap_point() {
+    let mut queue = ReassemblyQueue::new();
+    queue.insert(SeqNum::new(1), b"cd");
+    queue.insert(SeqNum::new(u32::MAX - 1), b"ab");
+    queue.insert(SeqNum::new(0), b"X");
+    assert_eq!(ranges(&queue), [(u32::MAX - 1, 3)]);
+    assert_eq!(queue.pop(SeqNum::new(u32::MAX - 1)).unwrap(), b"abXcd");
+}
@@ -0,0 +1,58 @@
+use std::time::Duration;
+use toytcp::rtt::RttEstimator;
+
+#[test]
+fn first_sample_sets_srtt_and_rttvar() {
+    let mut rtt = RttEstimator::new();
+    assert_eq!(rtt.rto, Duration::from_secs(1));
+    rtt.sample(Duration::from_secs(2));
+    assert_eq!(rtt.srtt, Some(Duration::from_secs(2)));
+    assert_eq!(rtt.rttvar, Duration::from_secs(1));
+    // RTO = SRTT + 4 * RTTVAR
+    assert_eq!(rtt.rto, Duration::from_secs(6));
+}
+
+#[test]
+fn smooths_later_samples() {
+    let mut rtt = RttEstimator::new();
+    rtt.sample(Duration::from_millis(800));
+    rtt.sample(Duration::from_millis(1600));
+    // SRTT = 7/8 * 800 + 1/8 * 1600, RTTVAR = 3/4 * 400 + 1/4 * 800
+    assert_eq!(rtt.srtt, Some(Duration::from_millis(900)));
+    assert_eq!(rtt.rttvar, Duration::from_millis(500));
+    assert_eq!(rtt.rto, Duration::from_millis(2900));
+}
+
+#[test]
+fn clamps_rto() {
+    let mut rtt = RttEstimator::new();
+    rtt.sample(Duration::from_millis(10));
+    assert_eq!(rtt.rto, Duration::from_secs(1));
+    rtt.sample(Duration::from_secs(100));
+    assert_eq!(rtt.rto, Duration::from_secs(60));
+}
+
+#[test]
+fn doubles_timeout_for_each_retransmission() {
+    let rtt = RttEstimator::new();
+    assert_eq!(rtt.backoff(1), Duration::from_secs(1));
+    assert_eq!(rtt.backoff(2), Duration::from_secs(2));
+    assert_eq!(rtt.backoff(4), Duration::from_secs(8));
+    assert_eq!(rtt.backoff(10), Duration::from_secs(60));
+}
+
+#[test]
+fn doubles_rto_on_timeout_until_next_sample() {
+    let mut rtt = RttEstimator::new();
+    rtt.on_timeout();
+    assert_eq!(rtt.rto, Duration::from_secs(2));
+    rtt.on_timeout();
+    assert_eq!(rtt.rto, Duration::from_secs(4));
+    // 新しく計測した RTT から計算し直す
+    rtt.sample(Duration::from_millis(100));
+    assert_eq!(rtt.rto, Duration::from_secs(1));
+    for _ in 0..10 {
+        rtt.on_timeout();
+    }
+    assert_eq!(rtt.rto, Duration::from_secs(60));
+}
@@ -0,0 +1,25 @@
+use std::cmp::Ordering;
+use toytcp::seqnum::SeqNum;
+
+#[test]
+fn compares_across_wrap_point() {
+    let before = SeqNum::new(u32::MAX - 10);
+    let after = before + 20;
+    assert_eq!(after, SeqNum::new(9));
+    assert!(before < after);
+    assert!(after > before);
+    assert_eq!(after - before, 20);
+    assert_eq!(after - 20, before);
+
+    let mut seq = before;
+    seq += 11;
+    assert_eq!(seq, SeqNum::new(0));
+}
+
+#[test]
+fn half_way_around_is_unordered() {
+    let a = SeqNum::new(1);
+    assert_eq!(a.partial_cmp(&(a + 0x8000_0000)), None);
+    assert_eq!(a.partial_cmp(&(a + 0x7fff_ffff)), Some(Ordering::Less));
+    assert_eq!(a.partial_cmp(&(a + 0x8000_0001)), Some(Ordering::Greater));
+}
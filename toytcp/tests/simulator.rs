@@ -0,0 +1,266 @@
+mod common;
+
+use common::{is_data, CLIENT_ADDR, SERVER_ADDR, SERVER_PORT};
+use pnet::packet::Packet;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::Duration;
+use toytcp::clock::ManualClock;
+use toytcp::congestion::CongestionAlgorithm;
+use toytcp::link::Link;
+use toytcp::loopback::LoopbackLink;
+use toytcp::packet::TCPPacket;
+use toytcp::seqnum::SeqNum;
+use toytcp::simulator::{Impairment, Rule, SimulatorConfig, SimulatorLink};
+use toytcp::socket::TcpStatus;
+use toytcp::tcp::TCP;
+use toytcp::tcpflags;
+
+/// seed で障害を加えたリンクに 100 個のセグメントを流し、(障害の履歴, 受信したセグメント列) を返す。
+fn run_segments(seed: u64) -> (Vec<(usize, Impairment)>, Vec<Vec<u8>>) {
+    let (a, b) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let link = SimulatorLink::new(
+        a,
+        SimulatorConfig {
+            seed,
+            loss_rate: 0.1,
+            duplicate_rate: 0.1,
+            truncate_rate: 0.1,
+            corrupt_rate: 0.1,
+            ..Default::default()
+        },
+    );
+    for i in 0..100 {
+        let mut packet = TCPPacket::new(8);
+        packet.set_seq(SeqNum::new(i));
+        packet.set_payload(&u64::from(i).to_be_bytes());
+        link.send_to(packet.packet(), SERVER_ADDR).unwrap();
+    }
+
+    let history = link.history();
+    let mut delivered = 100;
+    for (_, impairment) in &history {
+        match impairment {
+            Impairment::Drop => delivered -= 1,
+            Impairment::Duplicate => delivered += 1,
+            _ => {}
+        }
+    }
+    let received = (0..delivered).map(|_| b.recv().unwrap().2).collect();
+    (history, received)
+}
+
+#[test]
+fn same_seed_reproduces_same_impairments() {
+    let (history, received) = run_segments(42);
+    assert!(!history.is_empty());
+    assert_eq!(run_segments(42), (history.clone(), received));
+    assert_ne!(run_segments(43).0, history);
+}
+
+/// seq を付けた空のセグメントを生成する。
+fn segment(seq: u32) -> TCPPacket {
+    let mut packet = TCPPacket::new(0);
+    packet.set_seq(SeqNum::new(seq));
+    packet
+}
+
+#[test]
+fn delivers_delayed_segment_before_later_segments_on_virtual_clock() {
+    let (a, b) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let clock = Arc::new(ManualClock::new());
+    let link = SimulatorLink::with_clock(a, SimulatorConfig::default(), clock.clone());
+    link.add_rule(Rule::once(
+        Impairment::Delay(Duration::from_millis(100)),
+        |_| true,
+    ));
+    link.send_to(segment(0).packet(), SERVER_ADDR).unwrap();
+    // 遅延は仮想的な時計で測るので、時計を進めた後に送ったセグメントよりも必ず先に届く
+    clock.advance(Duration::from_millis(100));
+    link.send_to(segment(1).packet(), SERVER_ADDR).unwrap();
+    assert_eq!(b.recv().unwrap().2, segment(0).packet());
+    assert_eq!(b.recv().unwrap().2, segment(1).packet());
+}
+
+#[test]
+fn delivers_held_segment_without_following_segment() {
+    let (a, b) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let clock = Arc::new(ManualClock::new());
+    let config = SimulatorConfig {
+        reorder_rate: 1.0,
+        ..Default::default()
+    };
+    let max_delay = config.max_delay;
+    let link = SimulatorLink::with_clock(a, config, clock.clone());
+    link.send_to(segment(0).packet(), SERVER_ADDR).unwrap();
+    assert_eq!(link.history(), vec![(0, Impairment::Reorder)]);
+    // 追い越すセグメントが送られなくても、max_delay が経過すれば保留を解いて送る
+    clock.advance(max_delay);
+    assert_eq!(b.recv().unwrap().2, segment(0).packet());
+}
+
+/// client -> server 方向に障害を加えたリンクでファイル転送を行う。
+fn transfer_with_rule(rule: Rule) {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client_link = SimulatorLink::new(client_link, SimulatorConfig::default());
+    client_link.add_rule(rule);
+    let client = TCP::new(client_link.clone());
+    let server_thread = common::spawn_file_server(TCP::new(server_link));
+
+    let input = common::test_data(4000);
+    common::send_file(&client, &input);
+    assert_eq!(server_thread.join().unwrap(), input);
+    assert_eq!(client_link.history().len(), 1);
+}
+
+#[test]
+fn retransmits_dropped_segment() {
+    transfer_with_rule(Rule::once(Impairment::Drop, is_data));
+}
+
+#[test]
+fn discards_corrupted_segment() {
+    transfer_with_rule(Rule::once(
+        Impairment::Corrupt {
+            offset: 100,
+            bit: 3,
+        },
+        is_data,
+    ));
+}
+
+#[test]
+fn discards_truncated_segment() {
+    transfer_with_rule(Rule::once(Impairment::Truncate(30), is_data));
+}
+
+#[test]
+fn reassembles_reordered_segments() {
+    transfer_with_rule(Rule::once(Impairment::Reorder, is_data));
+}
+
+#[test]
+fn reassembles_delayed_segment() {
+    transfer_with_rule(Rule::once(
+        Impairment::Delay(Duration::from_millis(100)),
+        is_data,
+    ));
+}
+
+fn is_syn(packet: &TCPPacket) -> bool {
+    packet.get_flag() & tcpflags::SYN > 0
+}
+
+fn is_fin(packet: &TCPPacket) -> bool {
+    packet.get_flag() & tcpflags::FIN > 0
+}
+
+#[test]
+fn simultaneous_open_and_close() {
+    const PORT_A: u16 = 40001;
+    const PORT_B: u16 = 40002;
+    let (link_a, link_b) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    // SYN, SYN|ACK と FIN を遅らせて、お互いが送信したものが行き違うようにする
+    let link_a = SimulatorLink::new(link_a, SimulatorConfig::default());
+    let link_b = SimulatorLink::new(link_b, SimulatorConfig::default());
+    for link in [&link_a, &link_b] {
+        link.add_rule(Rule::once(
+            Impairment::Delay(Duration::from_millis(200)),
+            is_syn,
+        ));
+        link.add_rule(Rule::once(
+            Impairment::Delay(Duration::from_millis(200)),
+            is_fin,
+        ));
+    }
+    let a = TCP::new(link_a);
+    let b = TCP::new(link_b);
+
+    let barrier = Arc::new(Barrier::new(2));
+    let peer = {
+        let barrier = barrier.clone();
+        thread::spawn(move || {
+            let sock_id = b.connect_from(PORT_B, CLIENT_ADDR, PORT_A).unwrap();
+            b.send(sock_id, b"from b").unwrap();
+            let mut buffer = [0; 16];
+            let n = b.recv(sock_id, &mut buffer).unwrap();
+            barrier.wait();
+            b.close(sock_id).unwrap();
+            (buffer[..n].to_vec(), b.status(sock_id).unwrap())
+        })
+    };
+    let sock_id = a.connect_from(PORT_A, SERVER_ADDR, PORT_B).unwrap();
+    a.send(sock_id, b"from a").unwrap();
+    let mut buffer = [0; 16];
+    let n = a.recv(sock_id, &mut buffer).unwrap();
+    assert_eq!(&buffer[..n], b"from b");
+    barrier.wait();
+    a.close(sock_id).unwrap();
+    assert_eq!(a.status(sock_id).unwrap(), TcpStatus::TimeWait);
+
+    let (received, status) = peer.join().unwrap();
+    assert_eq!(received, b"from a");
+    assert_eq!(status, TcpStatus::TimeWait);
+}
+
+/// 一定間隔でデータセグメントを破棄するリンクで、CUBIC のソケットからファイル転送を行う。
+/// 転送中の cwnd の推移を記録して、ロスで beta 倍に下げてから再び増やしていることを確認する。
+#[test]
+fn cubic_window_recovers_on_lossy_link() {
+    let (client_link, server_link) = LoopbackLink::pair(CLIENT_ADDR, SERVER_ADDR);
+    let client_link = SimulatorLink::new(client_link, SimulatorConfig::default());
+    // 再送も含めて、50 個に1個のデータセグメントを破棄する
+    let sent = AtomicUsize::new(0);
+    client_link.add_rule(
+        Rule::once(Impairment::Drop, move |packet| {
+            is_data(packet) && sent.fetch_add(1, Ordering::SeqCst) % 50 == 49
+        })
+        .times(usize::MAX),
+    );
+    let client = TCP::new(client_link.clone());
+    let server_thread = common::spawn_file_server(TCP::new(server_link));
+
+    let input = common::test_data(200_000);
+    let sock_id = client.connect(SERVER_ADDR, SERVER_PORT).unwrap();
+    client
+        .set_congestion_control(sock_id, CongestionAlgorithm::Cubic)
+        .unwrap();
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = {
+        let client = client.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            let mut curve = Vec::new();
+            while !done.load(Ordering::SeqCst) {
+                let stats = client.stats(sock_id).unwrap();
+                let sample = (stats.cwnd, stats.ssthresh);
+                if curve.last() != Some(&sample) {
+                    curve.push(sample);
+                }
+                thread::sleep(Duration::from_micros(200));
+            }
+            curve
+        })
+    };
+    client.send(sock_id, &input).unwrap();
+    // close は送信した FIN が ack されるまで待つので、全てのデータが ack されるまでの推移を記録できる
+    client.close(sock_id).unwrap();
+    done.store(true, Ordering::SeqCst);
+    let curve = sampler.join().unwrap();
+    let stats = client.stats(sock_id).unwrap();
+    assert_eq!(server_thread.join().unwrap(), input);
+    assert!(stats.retransmissions + stats.fast_retransmissions >= 1);
+
+    // ロスを検出すると、ssthresh をその時点の cwnd の 0.7 倍にする (Reno であれば送信中のデータ量の半分になる)。
+    // 下げたあとも、ack されるにつれて再び増えていく。
+    // 記録の間隔の間に複数回ロスすることもあるので、いずれかのロスでそうなっていればよい。
+    let recovered = (1..curve.len()).any(|i| {
+        let ((before, ssthresh), (reduced, new_ssthresh)) = (curve[i - 1], curve[i]);
+        let ratio = new_ssthresh as f64 / before as f64;
+        ssthresh != new_ssthresh
+            && (0.6..0.8).contains(&ratio)
+            && curve[i..].iter().any(|&(later, _)| later > reduced)
+    });
+    assert!(recovered, "{:?}", curve);
+}
@@ -0,0 +1,105 @@
+use pnet::packet::Packet;
+use toytcp::packet::TCPPacket;
+use toytcp::seqnum::SeqNum;
+use toytcp::tcpoption::{self, TcpOption};
+
+#[test]
+fn builds_and_parses_options() {
+    let options = vec![
+        TcpOption::Mss(1460),
+        TcpOption::WindowScale(7),
+        TcpOption::SackPermitted,
+        TcpOption::Sack(vec![
+            (SeqNum::new(100), SeqNum::new(200)),
+            (SeqNum::new(u32::MAX - 10), SeqNum::new(10)),
+        ]),
+        TcpOption::Timestamps {
+            value: 1,
+            echo_reply: u32::MAX,
+        },
+    ];
+    let bytes = tcpoption::build(&options);
+    // 4 + 3 + 2 + 18 + 10 バイトを4バイト単位に埋める
+    assert_eq!(bytes.len(), 40);
+    assert_eq!(tcpoption::parse(&bytes).collect::<Vec<_>>(), options);
+}
+
+#[test]
+fn skips_no_operation_and_keeps_unknown_options() {
+    let bytes = [1, 1, 4, 2, 30, 4, 0xab, 0xcd, 0, 4, 2];
+    assert_eq!(
+        tcpoption::parse(&bytes).collect::<Vec<_>>(),
+        vec![
+            TcpOption::SackPermitted,
+            TcpOption::Unknown {
+                kind: 30,
+                data: vec![0xab, 0xcd],
+            },
+        ]
+    );
+    // 長さが壊れているオプション以降は読まない
+    assert_eq!(
+        tcpoption::parse(&[4, 2, 5, 40, 0, 0]).collect::<Vec<_>>(),
+        vec![TcpOption::SackPermitted]
+    );
+    assert_eq!(tcpoption::parse(&[4, 1]).collect::<Vec<_>>(), vec![]);
+}
+
+#[test]
+fn payload_follows_options() {
+    let mut packet = TCPPacket::new(5);
+    packet.set_options(&[TcpOption::SackPermitted]);
+    packet.set_payload(b"hello");
+    assert_eq!(packet.get_data_offset(), 6);
+    assert_eq!(
+        packet.options().collect::<Vec<_>>(),
+        vec![TcpOption::SackPermitted]
+    );
+    assert_eq!(packet.payload(), b"hello");
+    assert_eq!(packet.get_segment_len(), 5);
+}
+
+#[test]
+fn parses_linux_syn_options() {
+    // Linux が SYN に付けるオプション: MSS, SACK-permitted, Timestamps, NOP, Window Scale
+    let bytes = [
+        2, 4, 0x05, 0xb4, 4, 2, 8, 10, 0, 0, 0x30, 0x39, 0, 0, 0, 0, 1, 3, 3, 7,
+    ];
+    assert_eq!(
+        tcpoption::parse(&bytes).collect::<Vec<_>>(),
+        vec![
+            TcpOption::Mss(1460),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps {
+                value: 12345,
+                echo_reply: 0,
+            },
+            TcpOption::WindowScale(7),
+        ]
+    );
+}
+
+#[test]
+fn reads_payload_after_options_of_received_segment() {
+    // NOP, NOP, Timestamps の 12 バイトのオプションが付いたデータセグメント
+    let mut segment = vec![0; 20];
+    segment[12] = 8 << 4;
+    segment.extend_from_slice(&[1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2]);
+    segment.extend_from_slice(b"data");
+    let packet = TCPPacket::from(pnet::packet::tcp::TcpPacket::new(&segment).unwrap());
+    assert!(packet.has_valid_data_offset());
+    assert_eq!(packet.get_data_offset(), 8);
+    assert_eq!(packet.payload(), b"data");
+    assert_eq!(
+        packet.options().collect::<Vec<_>>(),
+        vec![TcpOption::Timestamps {
+            value: 1,
+            echo_reply: 2,
+        }]
+    );
+
+    // ヘッダがセグメントより長いと主張しているものは不正
+    segment[12] = 15 << 4;
+    let packet = TCPPacket::from(pnet::packet::tcp::TcpPacket::new(&segment).unwrap());
+    assert!(!packet.has_valid_data_offset());
+}